@@ -0,0 +1,26 @@
+//! Generates the gRPC service stubs for `proto/trilib_zvuk.proto`. The
+//! messages are written by hand in `src/grpc.rs`, so no `protoc` is needed;
+//! keep both in step with the .proto file.
+
+use tonic_build::manual::{Builder, Method, Service};
+
+fn method(name: &str, route: &str, input: &str, output: &str) -> tonic_build::manual::MethodBuilder {
+    Method::builder()
+        .name(name)
+        .route_name(route)
+        .input_type(format!("crate::grpc::{}", input))
+        .output_type(format!("crate::grpc::{}", output))
+        .codec_path("tonic_prost::ProstCodec")
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    let service = Service::builder()
+        .name("ZvukService")
+        .package("trilib.zvuk")
+        .method(method("download", "Download", "DownloadRequest", "DownloadProgress").server_streaming().build())
+        .method(method("get_job_status", "GetJobStatus", "GetJobStatusRequest", "Job").build())
+        .method(method("get_metadata", "GetMetadata", "GetMetadataRequest", "TrackMetadata").build())
+        .build();
+    Builder::new().build_client(false).compile(&[service]);
+}
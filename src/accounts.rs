@@ -0,0 +1,165 @@
+use std::{collections::HashMap, error::Error, fmt, sync::Mutex};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::{
+    config::{self, Selection},
+    failure::Category,
+    unix_now,
+};
+
+/// What the pool remembers about one account between downloads.
+#[derive(Default)]
+struct State {
+    last_used: Option<u64>,
+    benched_until: Option<u64>,
+    bench_reason: Option<String>,
+    downloads: u64,
+    failures: u64,
+}
+
+#[derive(Default)]
+struct Pool {
+    states: HashMap<String, State>,
+    /// Round-robin position in the name-sorted account list.
+    next: usize,
+}
+
+static POOL: Lazy<Mutex<Pool>> = Lazy::new(|| Mutex::new(Pool::default()));
+
+/// An account picked for one download.
+pub struct Lease {
+    pub name: String,
+    pub cookie: String,
+}
+
+/// Whether downloads may leave out `auth_cookie`.
+pub fn configured() -> bool {
+    !config::get().accounts.pool.is_empty()
+}
+
+/// Rejects a download without a cookie when there's no pool to fall back on.
+pub fn check_cookie(auth_cookie: &str) -> Result<(), String> {
+    if auth_cookie.is_empty() && !configured() {
+        return Err("auth_cookie is required; no [accounts.pool] is configured".to_string());
+    }
+    Ok(())
+}
+
+/// Picks an account that isn't benched and hasn't been tried yet, as
+/// `[accounts] selection` says.
+pub fn pick(tried: &[String]) -> Option<Lease> {
+    let accounts = &config::get().accounts;
+    let now = unix_now();
+    let mut pool = POOL.lock().unwrap();
+    let names: Vec<&String> = accounts.pool.keys().collect();
+    let usable = |pool: &Pool, name: &String| {
+        !tried.contains(name)
+            && pool
+                .states
+                .get(name)
+                .and_then(|s| s.benched_until)
+                .is_none_or(|until| until <= now)
+    };
+    let name = match accounts.selection {
+        Selection::RoundRobin => {
+            let start = pool.next;
+            let found = (0..names.len())
+                .map(|i| (start + i) % names.len())
+                .find(|&i| usable(&pool, names[i]));
+            if let Some(i) = found {
+                pool.next = i + 1;
+            }
+            found.map(|i| names[i])
+        }
+        Selection::LeastRecentlyUsed => names
+            .iter()
+            .filter(|name| usable(&pool, name))
+            .min_by_key(|name| pool.states.get(**name).and_then(|s| s.last_used))
+            .copied(),
+    }?;
+    let state = pool.states.entry(name.clone()).or_default();
+    state.last_used = Some(now);
+    state.benched_until = None;
+    Some(Lease { name: name.clone(), cookie: accounts.pool[name].clone() })
+}
+
+pub fn succeeded(name: &str) {
+    POOL.lock().unwrap().states.entry(name.to_string()).or_default().downloads += 1;
+}
+
+pub fn failed(name: &str) {
+    POOL.lock().unwrap().states.entry(name.to_string()).or_default().failures += 1;
+}
+
+/// Takes an account out of rotation for `[accounts] bench_secs`.
+pub fn bench(name: &str, reason: &str) {
+    let until = unix_now() + config::get().accounts.bench_secs;
+    tracing::warn!(account = name, "benching Zvuk account until {}: {}", until, reason);
+    let mut pool = POOL.lock().unwrap();
+    let state = pool.states.entry(name.to_string()).or_default();
+    state.failures += 1;
+    state.benched_until = Some(until);
+    state.bench_reason = Some(reason.to_string());
+}
+
+/// Whether a failure says the account itself is the problem.
+pub fn should_bench(category: Category) -> bool {
+    matches!(category, Category::AuthExpired | Category::RateLimited)
+}
+
+#[derive(Serialize)]
+pub struct Health {
+    pub name: String,
+    pub available: bool,
+    pub benched_until: Option<u64>,
+    /// Why it was last benched.
+    pub bench_reason: Option<String>,
+    pub last_used: Option<u64>,
+    pub downloads: u64,
+    pub failures: u64,
+}
+
+/// Every configured account and how it's doing; cookies aren't included.
+pub fn health() -> Vec<Health> {
+    let now = unix_now();
+    let pool = POOL.lock().unwrap();
+    config::get()
+        .accounts
+        .pool
+        .keys()
+        .map(|name| {
+            let state = pool.states.get(name);
+            let benched_until = state.and_then(|s| s.benched_until).filter(|until| *until > now);
+            Health {
+                name: name.clone(),
+                available: benched_until.is_none(),
+                benched_until,
+                bench_reason: state.and_then(|s| s.bench_reason.clone()),
+                last_used: state.and_then(|s| s.last_used),
+                downloads: state.map_or(0, |s| s.downloads),
+                failures: state.map_or(0, |s| s.failures),
+            }
+        })
+        .collect()
+}
+
+/// Every account was benched or failed for this download.
+#[derive(Debug)]
+pub struct PoolExhausted {
+    /// Category of the last account's failure.
+    pub category: Category,
+    pub last_error: Option<String>,
+}
+
+impl fmt::Display for PoolExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.last_error {
+            Some(e) => write!(f, "no Zvuk account left to try; last error: {}", e),
+            None => write!(f, "no Zvuk account available; all are benched"),
+        }
+    }
+}
+
+impl Error for PoolExhausted {}
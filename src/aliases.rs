@@ -0,0 +1,108 @@
+use std::{path::Path, sync::Mutex};
+
+use once_cell::sync::OnceCell;
+use rusqlite::{params, Connection, Row};
+use serde::Serialize;
+
+use crate::{db, unix_now};
+
+/// One known relationship between a cache hash, a Zvuk track and its ISRC.
+#[derive(Serialize, Clone, Debug)]
+pub struct Alias {
+    pub hash: String,
+    pub track_id: String,
+    pub isrc: Option<String>,
+    pub updated_at: i64,
+}
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS aliases (
+    hash       TEXT NOT NULL,
+    track_id   TEXT NOT NULL,
+    isrc       TEXT,
+    updated_at INTEGER NOT NULL,
+    PRIMARY KEY (hash, track_id)
+);
+CREATE INDEX IF NOT EXISTS aliases_track ON aliases (track_id);
+CREATE INDEX IF NOT EXISTS aliases_isrc ON aliases (isrc);
+";
+
+fn alias_from_row(row: &Row) -> rusqlite::Result<Alias> {
+    Ok(Alias {
+        hash: row.get(0)?,
+        track_id: row.get(1)?,
+        isrc: row.get(2)?,
+        updated_at: row.get(3)?,
+    })
+}
+
+pub struct AliasStore {
+    conn: Mutex<Connection>,
+}
+
+static STORE: OnceCell<AliasStore> = OnceCell::new();
+
+pub fn store() -> &'static AliasStore {
+    STORE.get().expect("alias store is not open")
+}
+
+/// Opens the alias table and backfills it from completed jobs, which is where
+/// the relationship lived before this table existed.
+pub fn open(path: &Path) -> rusqlite::Result<()> {
+    let conn = db::open(path)?;
+    conn.execute_batch(SCHEMA)?;
+    let has_jobs: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'jobs')",
+        [],
+        |row| row.get(0),
+    )?;
+    if has_jobs {
+        conn.execute(
+            "INSERT OR IGNORE INTO aliases (hash, track_id, isrc, updated_at)
+             SELECT hash, track_id, NULL, MAX(updated_at) FROM jobs
+             WHERE state = 'done' GROUP BY hash, track_id",
+            [],
+        )?;
+    }
+    STORE
+        .set(AliasStore { conn: Mutex::new(conn) })
+        .ok()
+        .expect("alias store opened twice");
+    Ok(())
+}
+
+impl AliasStore {
+    /// Records that `hash` holds `track_id`. A known ISRC is never overwritten
+    /// with `None`.
+    pub fn record(&self, hash: &str, track_id: &str, isrc: Option<&str>) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO aliases (hash, track_id, isrc, updated_at) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (hash, track_id) DO UPDATE SET
+                isrc = COALESCE(excluded.isrc, isrc),
+                updated_at = excluded.updated_at",
+            params![hash, track_id, isrc, unix_now() as i64],
+        )?;
+        Ok(())
+    }
+
+    fn query(&self, column: &str, value: &str) -> rusqlite::Result<Vec<Alias>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT hash, track_id, isrc, updated_at FROM aliases WHERE {} = ?1 ORDER BY updated_at DESC",
+            column
+        ))?;
+        stmt.query_map([value], alias_from_row)?.collect()
+    }
+
+    pub fn by_hash(&self, hash: &str) -> rusqlite::Result<Vec<Alias>> {
+        self.query("hash", hash)
+    }
+
+    pub fn by_track(&self, track_id: &str) -> rusqlite::Result<Vec<Alias>> {
+        self.query("track_id", track_id)
+    }
+
+    pub fn by_isrc(&self, isrc: &str) -> rusqlite::Result<Vec<Alias>> {
+        self.query("isrc", isrc)
+    }
+}
@@ -0,0 +1,353 @@
+use std::{collections::HashMap, env};
+
+use axum::{
+    extract::{Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hyper::StatusCode;
+use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
+use once_cell::sync::Lazy;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{error::ApiError, jobs::Job, tenant};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Role {
+    ReadOnly,
+    Submit,
+    Admin,
+}
+
+impl Role {
+    fn parse(s: &str) -> Option<Role> {
+        match s {
+            "read" | "read-only" | "readonly" => Some(Role::ReadOnly),
+            "submit" => Some(Role::Submit),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// `TRI_ZVUK_API_KEYS` is a comma separated list of `role:key` pairs. When it is
+/// unset every request is let through, as before keys existed.
+static API_KEYS: Lazy<HashMap<String, Role>> = Lazy::new(|| {
+    parse_keys(&env::var("TRI_ZVUK_API_KEYS").unwrap_or_default())
+        .unwrap_or_else(|e| panic!("TRI_ZVUK_API_KEYS: {}", e))
+});
+
+fn parse_keys(spec: &str) -> Result<HashMap<String, Role>, String> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|pair| {
+            let (role, key) = pair
+                .split_once(':')
+                .ok_or_else(|| "entry must be role:key".to_string())?;
+            let role = Role::parse(role).ok_or_else(|| format!("unknown API key role: {}", role))?;
+            if key.is_empty() {
+                return Err("entry has an empty key".to_string());
+            }
+            Ok((key.to_string(), role))
+        })
+        .collect()
+}
+
+/// HMAC secret for scoped tokens. Without `TRI_ZVUK_JWT_SECRET` a random one is
+/// generated, so tokens don't survive a restart.
+static JWT_SECRET: Lazy<Vec<u8>> = Lazy::new(|| match env::var("TRI_ZVUK_JWT_SECRET") {
+    Ok(secret) => secret.into_bytes(),
+    Err(_) => {
+        let mut secret = vec![0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        secret
+    }
+});
+
+pub const MAX_TOKEN_TTL_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Download,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Claims {
+    pub exp: u64,
+    pub iat: u64,
+    pub scope: Vec<Scope>,
+    /// Track IDs the token may download; any ID if absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ids: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+}
+
+/// Stable identity used for per-client limits. [`require`] sets it from the
+/// presented key or token; otherwise the client's IP is used.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ClientId(pub String);
+
+impl ClientId {
+    fn credential(kind: &str, secret: &str) -> Self {
+        let digest = hex::encode(Sha256::digest(secret.as_bytes()));
+        ClientId(format!("{}:{}", kind, &digest[..16]))
+    }
+}
+
+/// Who made the request, inserted into request extensions by [`require`].
+#[derive(Clone, Debug)]
+pub enum Principal {
+    Key { tenant: Option<String> },
+    Token(Claims),
+}
+
+impl Principal {
+    pub fn may_download(&self, id: &str) -> bool {
+        match self {
+            Principal::Key { .. } => true,
+            Principal::Token(claims) => claims
+                .ids
+                .as_ref()
+                .is_none_or(|ids| ids.iter().any(|allowed| allowed == id)),
+        }
+    }
+
+    pub fn tenant(&self) -> Option<&str> {
+        match self {
+            Principal::Key { tenant } => tenant.as_deref(),
+            Principal::Token(claims) => claims.tenant.as_deref(),
+        }
+    }
+
+    /// Keys and tokens of a tenant only see that tenant's jobs.
+    pub fn may_see(&self, job: &Job) -> bool {
+        self.tenant().is_none_or(|tenant| job.tenant.as_deref() == Some(tenant))
+    }
+}
+
+pub fn init() {
+    Lazy::force(&API_KEYS);
+    Lazy::force(&JWT_SECRET);
+}
+
+pub fn mint(
+    scope: Vec<Scope>,
+    ids: Option<Vec<String>>,
+    tenant: Option<String>,
+    ttl_secs: u64,
+) -> Result<(String, u64), jsonwebtoken::errors::Error> {
+    let iat = crate::unix_now();
+    let claims = Claims { exp: iat + ttl_secs, iat, scope, ids, tenant };
+    let token = jsonwebtoken::encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(&JWT_SECRET),
+    )?;
+    Ok((token, claims.exp))
+}
+
+fn verify(token: &str) -> Option<Claims> {
+    let key = DecodingKey::from_secret(&JWT_SECRET);
+    jsonwebtoken::decode::<Claims>(token, &key, &Validation::default())
+        .ok()
+        .map(|data| data.claims)
+}
+
+fn token_allows(claims: &Claims, needed: Role) -> bool {
+    match needed {
+        Role::ReadOnly => claims.scope.contains(&Scope::Read),
+        Role::Submit => claims.scope.contains(&Scope::Download),
+        Role::Admin => false,
+    }
+}
+
+fn presented_key(headers: &HeaderMap) -> Option<&str> {
+    if let Some(key) = headers.get("x-api-key").and_then(|h| h.to_str().ok()) {
+        return Some(key);
+    }
+    headers
+        .get(hyper::header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+}
+
+fn forbidden(error: String) -> Response {
+    ApiError::new(StatusCode::FORBIDDEN, error).into_response()
+}
+
+/// Why [`authenticate`] turned a caller away.
+pub enum Denied {
+    /// No credential, or one that isn't known, while keys are configured.
+    Unauthenticated,
+    /// The credential doesn't cover what was asked for.
+    Forbidden(String),
+}
+
+/// Checks the key or token in `headers` against `needed`. `None` means
+/// nobody was identified, which is allowed only while no keys are
+/// configured.
+pub fn authenticate(headers: &HeaderMap, needed: Role) -> Result<Option<(Principal, ClientId)>, Denied> {
+    authenticate_with(&API_KEYS, headers, needed)
+}
+
+fn authenticate_with(
+    keys: &HashMap<String, Role>,
+    headers: &HeaderMap,
+    needed: Role,
+) -> Result<Option<(Principal, ClientId)>, Denied> {
+    let presented = presented_key(headers);
+
+    if let Some((key, role)) = presented.and_then(|k| keys.get_key_value(k)) {
+        if *role < needed {
+            return Err(Denied::Forbidden(format!("{:?} key can't access this route", role)));
+        }
+        let tenant = tenant::for_key(key);
+        return Ok(Some((Principal::Key { tenant }, ClientId::credential("key", key))));
+    }
+    if let Some(token) = presented
+        && let Some(claims) = verify(token)
+    {
+        if !token_allows(&claims, needed) {
+            return Err(Denied::Forbidden("token scope doesn't cover this route".to_string()));
+        }
+        return Ok(Some((Principal::Token(claims), ClientId::credential("token", token))));
+    }
+    if keys.is_empty() {
+        return Ok(None);
+    }
+    Err(Denied::Unauthenticated)
+}
+
+pub async fn require(State(needed): State<Role>, mut req: Request, next: Next) -> Response {
+    match authenticate(req.headers(), needed) {
+        Ok(Some((principal, client))) => {
+            req.extensions_mut().insert(principal);
+            req.extensions_mut().insert(client);
+            next.run(req).await
+        }
+        Ok(None) => next.run(req).await,
+        Err(Denied::Forbidden(error)) => forbidden(error),
+        Err(Denied::Unauthenticated) => {
+            ApiError::new(StatusCode::UNAUTHORIZED, "missing or unknown API key").into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROLES: [Role; 3] = [Role::ReadOnly, Role::Submit, Role::Admin];
+
+    fn keys() -> HashMap<String, Role> {
+        parse_keys("read:r-key, submit:s-key, admin:a-key").unwrap()
+    }
+
+    fn bearer(credential: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(hyper::header::AUTHORIZATION, format!("Bearer {}", credential).parse().unwrap());
+        headers
+    }
+
+    fn token(scope: Vec<Scope>, ids: Option<Vec<String>>) -> String {
+        mint(scope, ids, None, 60).unwrap().0
+    }
+
+    #[test]
+    fn keys_reach_route_groups_up_to_their_role() {
+        let keys = keys();
+        for (key, role) in [("r-key", Role::ReadOnly), ("s-key", Role::Submit), ("a-key", Role::Admin)] {
+            for needed in ROLES {
+                let result = authenticate_with(&keys, &bearer(key), needed);
+                if role >= needed {
+                    assert!(matches!(result, Ok(Some((Principal::Key { .. }, _)))), "{:?} on {:?}", role, needed);
+                } else {
+                    assert!(matches!(result, Err(Denied::Forbidden(_))), "{:?} on {:?}", role, needed);
+                }
+            }
+        }
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", "s-key".parse().unwrap());
+        assert!(matches!(authenticate_with(&keys, &headers, Role::Submit), Ok(Some(_))));
+    }
+
+    #[test]
+    fn unknown_or_missing_keys_are_only_let_through_without_keys() {
+        for needed in ROLES {
+            assert!(matches!(authenticate_with(&keys(), &bearer("nope"), needed), Err(Denied::Unauthenticated)));
+            assert!(matches!(authenticate_with(&keys(), &HeaderMap::new(), needed), Err(Denied::Unauthenticated)));
+            assert!(matches!(authenticate_with(&HashMap::new(), &HeaderMap::new(), needed), Ok(None)));
+        }
+    }
+
+    #[test]
+    fn key_specs_are_parsed() {
+        let keys = parse_keys(" readonly:a ,read-only:b,,submit:c:d ").unwrap();
+        assert_eq!(keys.len(), 3);
+        assert_eq!(keys["a"], Role::ReadOnly);
+        assert_eq!(keys["b"], Role::ReadOnly);
+        assert_eq!(keys["c:d"], Role::Submit);
+        assert!(parse_keys("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_key_specs_are_rejected() {
+        for spec in ["admin", "read:a,owner:b", "submit:", ":key"] {
+            assert!(parse_keys(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn tokens_reach_route_groups_their_scope_covers() {
+        let keys = keys();
+        let cases = [
+            (vec![Scope::Read], [true, false]),
+            (vec![Scope::Download], [false, true]),
+            (vec![Scope::Read, Scope::Download], [true, true]),
+        ];
+        for (scope, allowed) in cases {
+            let token = token(scope.clone(), None);
+            for (needed, allowed) in [Role::ReadOnly, Role::Submit].into_iter().zip(allowed) {
+                let result = authenticate_with(&keys, &bearer(&token), needed);
+                assert_eq!(matches!(result, Ok(Some((Principal::Token(_), _)))), allowed, "{:?} on {:?}", scope, needed);
+            }
+        }
+    }
+
+    #[test]
+    fn tokens_never_grant_admin() {
+        let token = token(vec![Scope::Read, Scope::Download], None);
+        for keys in [keys(), HashMap::new()] {
+            assert!(matches!(authenticate_with(&keys, &bearer(&token), Role::Admin), Err(Denied::Forbidden(_))));
+        }
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let now = crate::unix_now();
+        let claims = Claims { exp: now - 3600, iat: now - 7200, scope: vec![Scope::Download], ids: None, tenant: None };
+        let expired = jsonwebtoken::encode(&Header::default(), &claims, &EncodingKey::from_secret(&JWT_SECRET)).unwrap();
+        assert!(verify(&expired).is_none());
+        assert!(matches!(authenticate_with(&keys(), &bearer(&expired), Role::Submit), Err(Denied::Unauthenticated)));
+    }
+
+    #[test]
+    fn tokens_only_download_their_ids() {
+        let scoped = Principal::Token(verify(&token(vec![Scope::Download], Some(vec!["1".into(), "2".into()]))).unwrap());
+        assert!(scoped.may_download("1"));
+        assert!(scoped.may_download("2"));
+        assert!(!scoped.may_download("3"));
+
+        let any = Principal::Token(verify(&token(vec![Scope::Download], None)).unwrap());
+        assert!(any.may_download("3"));
+        assert!(Principal::Key { tenant: None }.may_download("3"));
+    }
+}
@@ -0,0 +1,9 @@
+use std::process::ExitCode;
+
+use clap::Parser;
+use trilib_zvuk::cli::{self, Cli};
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    cli::run(Cli::parse()).await
+}
@@ -0,0 +1,237 @@
+use std::{error::Error, path::PathBuf, process::ExitCode};
+
+use clap::{Args, Parser, Subcommand};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    accounts, aliases, cleanup_incomplete, config, entry_dir, gc, manifest, save_by_id, tenant, throttle, transcode,
+    zvuk, DownloadZVUK, EntrySnapshot, CACHEDIR,
+};
+
+/// One-off downloads and lookups using the service's code, without running it.
+/// Reads the same config file and environment variables as the server.
+#[derive(Parser)]
+#[command(name = "trilib-zvuk", version)]
+pub struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Download a track into a cache entry and print its manifest.
+    Dl(DlArgs),
+    /// Print a track's metadata as JSON.
+    Meta {
+        #[arg(long)]
+        id: String,
+        #[command(flatten)]
+        session: Session,
+    },
+    /// Search Zvuk and print one page of results as JSON.
+    Search {
+        query: String,
+        /// track, album or artist.
+        #[arg(long, default_value = "track", value_parser = enum_arg::<zvuk::SearchType>)]
+        kind: zvuk::SearchType,
+        #[arg(long, default_value_t = 20, value_parser = clap::value_parser!(u32).range(1..=100))]
+        limit: u32,
+        /// `next_cursor` of the previous page.
+        #[arg(long)]
+        cursor: Option<String>,
+        #[command(flatten)]
+        session: Session,
+    },
+    /// Cache maintenance.
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// Remove empty entry directories, like `POST /admin/gc`.
+    Gc {
+        /// Cache root; `[cache] dir` or `TRI_CACHE` if absent.
+        #[arg(long)]
+        dir: Option<PathBuf>,
+    },
+}
+
+#[derive(Args)]
+struct DlArgs {
+    #[arg(long)]
+    id: String,
+    /// Cache hash to store the track under; the track ID if absent.
+    #[arg(long)]
+    hash: Option<String>,
+    /// Cache root, laid out like the server's; `[cache] dir` or `TRI_CACHE`
+    /// if absent.
+    #[arg(long)]
+    out: Option<PathBuf>,
+    #[command(flatten)]
+    session: Session,
+    /// Also save lyrics into the entry.
+    #[arg(long)]
+    lyrics: bool,
+    /// Measure loudness and add ReplayGain tags.
+    #[arg(long)]
+    normalize: bool,
+    /// Re-encode the best stream: mp3, opus or aac.
+    #[arg(long, value_parser = enum_arg::<transcode::Codec>)]
+    transcode: Option<transcode::Codec>,
+    /// Transcode bitrate in kbps.
+    #[arg(long, requires = "transcode")]
+    bitrate: Option<u32>,
+    /// as_requested, prefer_explicit, prefer_clean or both.
+    #[arg(long, value_parser = enum_arg::<config::ExplicitPolicy>)]
+    explicit_policy: Option<config::ExplicitPolicy>,
+    /// Download bandwidth in kilobits per second.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_kbps: Option<u32>,
+}
+
+#[derive(Args)]
+struct Session {
+    /// Zvuk session cookie; `dl` falls back on `[accounts.pool]`.
+    #[arg(long, conflicts_with = "cookie_file")]
+    cookie: Option<String>,
+    /// File holding the Zvuk session cookie.
+    #[arg(long)]
+    cookie_file: Option<PathBuf>,
+    /// Named proxy from `[proxies]`; `[upstream] proxy` if absent.
+    #[arg(long)]
+    proxy: Option<String>,
+}
+
+impl Session {
+    fn cookie(&self) -> Result<Option<String>, Box<dyn Error>> {
+        match (&self.cookie, &self.cookie_file) {
+            (Some(cookie), _) => Ok(Some(cookie.clone())),
+            (None, Some(path)) => std::fs::read_to_string(path)
+                .map(|cookie| Some(cookie.trim().to_string()))
+                .map_err(|e| format!("couldn't read {}: {}", path.display(), e).into()),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// Parses the same spelling the HTTP API accepts for a serde enum.
+fn enum_arg<T: DeserializeOwned>(s: &str) -> Result<T, String> {
+    serde_json::from_value(serde_json::Value::String(s.to_string())).map_err(|e| e.to_string())
+}
+
+fn print(value: &impl Serialize) -> Result<(), Box<dyn Error>> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+/// Runs one command; errors go to stderr and exit with status 1.
+pub async fn run(cli: Cli) -> ExitCode {
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_max_level(tracing::Level::WARN)
+        .init();
+    config::init();
+    let result = match cli.command {
+        Command::Dl(args) => dl(args).await,
+        Command::Meta { id, session } => meta(&id, &session).await,
+        Command::Search { query, kind, limit, cursor, session } => {
+            search(&query, kind, limit, cursor.as_deref(), &session).await
+        }
+        Command::Cache { command: CacheCommand::Gc { dir } } => collect_garbage(dir).await,
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Points the cache somewhere else; must run before anything reads it.
+fn set_cache_dir(dir: Option<PathBuf>) {
+    if let Some(dir) = dir {
+        config::update(|config| config.cache.dir = Some(dir));
+    }
+}
+
+async fn dl(args: DlArgs) -> Result<(), Box<dyn Error>> {
+    set_cache_dir(args.out);
+    manifest::init();
+    zvuk::init();
+    zvuk::check_proxy(args.session.proxy.as_deref())?;
+    let auth_cookie = args.session.cookie()?.unwrap_or_default();
+    accounts::check_cookie(&auth_cookie)?;
+    let hash = tenant::scheme(None).canonicalize(args.hash.as_deref().unwrap_or(&args.id))?;
+
+    tokio::fs::create_dir_all(&*CACHEDIR).await?;
+    let db = config::get().jobs.db_path.clone().unwrap_or_else(|| CACHEDIR.join("jobs.sqlite3"));
+    aliases::open(&db)?;
+
+    let params = DownloadZVUK {
+        id: args.id,
+        hash,
+        auth_cookie,
+        transcode: args.transcode.map(|codec| transcode::Transcode { codec, bitrate: args.bitrate }),
+        proxy: args.session.proxy,
+        include_lyrics: args.lyrics,
+        normalize: args.normalize,
+        label: None,
+        explicit_policy: args.explicit_policy,
+        checksums: false,
+        priority: None,
+        not_before: None,
+        idempotency_key: None,
+        max_kbps: args.max_kbps,
+    };
+    let entry = entry_dir(&params.hash);
+    let before = EntrySnapshot::take(&entry).await?;
+    let saved = zvuk::with_proxy(params.proxy.clone(), async {
+        throttle::with_limit(params.max_kbps, save_by_id(&params)).await.map_err(|e| e.to_string())
+    })
+    .await;
+    if let Err(e) = saved {
+        if let Err(e) = cleanup_incomplete(&entry, &before).await {
+            tracing::warn!("couldn't clean up {}: {}", entry.display(), e);
+        }
+        return Err(e.into());
+    }
+    eprintln!("saved to {}", entry.display());
+    print(&manifest::read(&entry).await?)
+}
+
+async fn meta(id: &str, session: &Session) -> Result<(), Box<dyn Error>> {
+    zvuk::init();
+    zvuk::check_proxy(session.proxy.as_deref())?;
+    let cookie = session.cookie()?;
+    let meta = zvuk::with_proxy(session.proxy.clone(), async {
+        zvuk::track_meta(id, cookie.as_deref()).await.map_err(|e| e.to_string())
+    })
+    .await?;
+    print(&meta)
+}
+
+async fn search(
+    query: &str,
+    kind: zvuk::SearchType,
+    limit: u32,
+    cursor: Option<&str>,
+    session: &Session,
+) -> Result<(), Box<dyn Error>> {
+    zvuk::init();
+    zvuk::check_proxy(session.proxy.as_deref())?;
+    let cookie = session.cookie()?;
+    let page = zvuk::with_proxy(session.proxy.clone(), async {
+        zvuk::search(query, kind, limit, cursor, cookie.as_deref()).await.map_err(|e| e.to_string())
+    })
+    .await?;
+    print(&page)
+}
+
+async fn collect_garbage(dir: Option<PathBuf>) -> Result<(), Box<dyn Error>> {
+    set_cache_dir(dir);
+    print(&gc::sweep().await?)
+}
@@ -0,0 +1,159 @@
+use std::{error::Error, fmt::Write as _, path::Path};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config, entry_dir,
+    jobs::{self, JobState},
+    manifest::{self, FileRecord, Manifest},
+    source,
+};
+
+pub const PLAYLIST_FILE: &str = "playlist.m3u8";
+
+/// What a multi-track entry was made from.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Release,
+    Playlist,
+    /// A track list given in the request.
+    Tracks,
+}
+
+/// The ordered tracks of an entry holding a release or playlist; each track
+/// is stored in its own entry.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Collection {
+    pub kind: Kind,
+    /// Every track's job has settled.
+    pub complete: bool,
+    pub tracks: Vec<Track>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Track {
+    /// 1-based place in the release or playlist.
+    pub position: usize,
+    pub id: String,
+    /// Entry the track is stored in.
+    pub hash: String,
+    /// Absent if the track was already cached.
+    pub job: Option<i64>,
+    pub state: JobState,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub artist: Option<String>,
+    #[serde(default)]
+    pub duration: Option<u64>,
+    /// File the playlist points at, relative to the collection's entry.
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Files of the track's entry, as listed in its manifest.
+    #[serde(default)]
+    pub files: Vec<FileRecord>,
+}
+
+impl Track {
+    /// A track downloaded by `job`, or already cached if there's none.
+    pub fn new(position: usize, id: &str, hash: &str, job: Option<i64>) -> Self {
+        Track {
+            position,
+            id: id.to_string(),
+            hash: hash.to_string(),
+            job,
+            state: if job.is_some() { JobState::Queued } else { JobState::Done },
+            title: None,
+            artist: None,
+            duration: None,
+            path: None,
+            files: Vec::new(),
+        }
+    }
+}
+
+/// Refreshes run one at a time, since every finishing track triggers one.
+static REFRESH: Lazy<tokio::sync::Mutex<()>> = Lazy::new(|| tokio::sync::Mutex::new(()));
+
+/// Writes the entry for a collection whose tracks were just enqueued.
+pub async fn create(hash: &str, id: &str, title: Option<&str>, kind: Kind, tracks: Vec<Track>) -> Result<(), Box<dyn Error>> {
+    let entry = entry_dir(hash);
+    tokio::fs::create_dir_all(&entry).await?;
+    let mut manifest = Manifest::new(id, hash, Vec::new(), None);
+    manifest.title = title.map(String::from);
+    manifest.collection = Some(Collection { kind, complete: false, tracks });
+    let _guard = REFRESH.lock().await;
+    write(&entry, manifest).await
+}
+
+/// Picks up the state and files of every track and rewrites the playlist
+/// and manifest.
+pub async fn refresh(hash: &str) -> Result<(), Box<dyn Error>> {
+    let _guard = REFRESH.lock().await;
+    let entry = entry_dir(hash);
+    let mut manifest = manifest::read(&entry).await?;
+    let Some(collection) = manifest.collection.as_mut() else {
+        return Err(format!("{} isn't a collection", hash).into());
+    };
+    for track in &mut collection.tracks {
+        if let Some(job) = track.job
+            && let Ok(Some(job)) = jobs::queue().store.get(job)
+        {
+            track.state = job.state;
+        }
+        let Ok(stored) = manifest::read(&entry_dir(&track.hash)).await else {
+            track.path = None;
+            track.files.clear();
+            continue;
+        };
+        track.title = stored.title.or(track.title.take());
+        track.artist = stored.artist.or(track.artist.take());
+        track.duration = stored.duration.or(track.duration);
+        track.path = playable(&stored.files).map(|name| format!("../../{}/{}/{}", track.hash, source(), name));
+        track.files = stored.files;
+    }
+    collection.complete = collection.tracks.iter().all(|t| t.state.is_settled());
+    write(&entry, manifest).await
+}
+
+/// The file to play: the first configured quality present, else a transcode.
+fn playable(files: &[FileRecord]) -> Option<&str> {
+    let stem_is = |file: &&FileRecord, stem: &str| file.name.split_once('.').is_some_and(|(s, _)| s == stem);
+    config::get()
+        .qualities
+        .iter()
+        .map(|q| q.as_str())
+        .chain(["transcoded"])
+        .find_map(|stem| files.iter().find(|f| stem_is(f, stem)))
+        .map(|f| f.name.as_str())
+}
+
+fn playlist(manifest: &Manifest, collection: &Collection) -> String {
+    let mut m3u = String::from("#EXTM3U\n");
+    if let Some(title) = &manifest.title {
+        let _ = writeln!(m3u, "#PLAYLIST:{}", title);
+    }
+    for track in &collection.tracks {
+        let Some(path) = &track.path else {
+            continue;
+        };
+        let duration = track.duration.map_or(-1, |d| d as i64);
+        let name = match (&track.artist, &track.title) {
+            (Some(artist), Some(title)) => format!("{} - {}", artist, title),
+            (None, Some(title)) => title.clone(),
+            _ => track.id.clone(),
+        };
+        let _ = writeln!(m3u, "#EXTINF:{},{}\n{}", duration, name, path);
+    }
+    m3u
+}
+
+async fn write(entry: &Path, mut manifest: Manifest) -> Result<(), Box<dyn Error>> {
+    let collection = manifest.collection.as_ref().ok_or("not a collection")?;
+    let path = entry.join(PLAYLIST_FILE);
+    tokio::fs::write(&path, playlist(&manifest, collection)).await?;
+    manifest.files = vec![manifest::file_record(&path).await?];
+    manifest::export(entry, &manifest).await
+}
@@ -0,0 +1,107 @@
+use std::{error::Error, path::Path};
+
+use serde::Serialize;
+
+use crate::{manifest, metadata, transcode};
+
+/// Qualities whose durations differ by more than this are flagged.
+const DURATION_TOLERANCE_SECS: f64 = 2.0;
+
+#[derive(Serialize)]
+pub struct Quality {
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+    pub codec: Option<String>,
+    pub bitrate_kbps: Option<u32>,
+    pub duration_secs: Option<f64>,
+    /// False when ffprobe couldn't read the file and bitrate and duration
+    /// were estimated from its size and the manifest's duration.
+    pub probed: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnomalyKind {
+    MidLargerThanBest,
+    IdenticalFiles,
+    DurationMismatch,
+}
+
+#[derive(Serialize)]
+pub struct Anomaly {
+    pub kind: AnomalyKind,
+    pub files: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub qualities: Vec<Quality>,
+    pub anomalies: Vec<Anomaly>,
+}
+
+fn stem(q: &Quality) -> &str {
+    Path::new(&q.name).file_stem().and_then(|s| s.to_str()).unwrap_or_default()
+}
+
+async fn quality(path: &Path, manifest_duration: Option<u64>) -> Result<Quality, Box<dyn Error>> {
+    let record = manifest::file_record(path).await?;
+    let (codec, duration_secs, bitrate_kbps, probed) = match transcode::probe(path).await {
+        Ok(p) => (p.codec, p.duration_secs, p.bitrate_kbps, true),
+        Err(e) => {
+            tracing::debug!("couldn't probe {}: {}", path.display(), e);
+            let duration = manifest_duration.filter(|d| *d > 0).map(|d| d as f64);
+            let bitrate = duration.map(|d| (record.size as f64 * 8.0 / d / 1000.0).round() as u32);
+            (None, duration, bitrate, false)
+        }
+    };
+    Ok(Quality {
+        name: record.name,
+        size: record.size,
+        sha256: record.sha256,
+        codec,
+        bitrate_kbps,
+        duration_secs,
+        probed,
+    })
+}
+
+/// Measures every stored audio file in the entry and flags qualities that
+/// look wrong relative to each other.
+pub async fn run(entry: &Path) -> Result<Report, Box<dyn Error>> {
+    let manifest_duration = manifest::read(entry).await.ok().and_then(|m| m.duration);
+    let mut qualities = Vec::new();
+    for path in metadata::audio_files(entry).await? {
+        qualities.push(quality(&path, manifest_duration).await?);
+    }
+
+    let mut anomalies = Vec::new();
+    let best = qualities.iter().find(|q| stem(q) == "best");
+    let mid = qualities.iter().find(|q| stem(q) == "mid");
+    if let (Some(best), Some(mid)) = (best, mid)
+        && mid.size > best.size
+    {
+        anomalies.push(Anomaly {
+            kind: AnomalyKind::MidLargerThanBest,
+            files: vec![best.name.clone(), mid.name.clone()],
+        });
+    }
+    for (i, a) in qualities.iter().enumerate() {
+        for b in &qualities[i + 1..] {
+            if a.sha256 == b.sha256 {
+                anomalies.push(Anomaly {
+                    kind: AnomalyKind::IdenticalFiles,
+                    files: vec![a.name.clone(), b.name.clone()],
+                });
+            } else if let (Some(da), Some(db)) = (a.duration_secs, b.duration_secs)
+                && (da - db).abs() > DURATION_TOLERANCE_SECS
+            {
+                anomalies.push(Anomaly {
+                    kind: AnomalyKind::DurationMismatch,
+                    files: vec![a.name.clone(), b.name.clone()],
+                });
+            }
+        }
+    }
+    Ok(Report { qualities, anomalies })
+}
@@ -0,0 +1,22 @@
+use std::path::Path;
+
+use rusqlite::Connection;
+
+/// Opens a connection to the service database. Job state and the alias table
+/// share one file; each store keeps its own connection.
+pub fn open(path: &Path) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.busy_timeout(std::time::Duration::from_secs(5))?;
+    Ok(conn)
+}
+
+/// Adds `column` to `table` unless it's already there, for schema upgrades of
+/// databases created by older versions.
+pub fn ensure_column(conn: &Connection, table: &str, column: &str, decl: &str) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare(&format!("SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1", table))?;
+    if !stmt.exists([column])? {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl), [])?;
+    }
+    Ok(())
+}
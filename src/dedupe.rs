@@ -0,0 +1,136 @@
+use std::{
+    fs::Metadata,
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{config, gc, manifest::{self, FileRecord}, CACHEDIR, PART_EXT};
+
+/// Directory in the cache root holding one copy of each audio file, named by
+/// its SHA-256. Entries hard-link to it, so a blob's link count is its
+/// reference count.
+pub const BLOB_DIR: &str = ".blobs";
+
+fn blob_path(sha256: &str) -> PathBuf {
+    CACHEDIR.join(BLOB_DIR).join(&sha256[..2]).join(sha256)
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    path.with_file_name(format!("{}.link.{}", name, PART_EXT))
+}
+
+#[cfg(unix)]
+fn links(meta: &Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::nlink(meta)
+}
+
+#[cfg(not(unix))]
+fn links(_meta: &Metadata) -> u64 {
+    1
+}
+
+/// Device and inode, to tell hard links to the same file apart from copies.
+#[cfg(unix)]
+pub fn file_id(meta: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+pub fn file_id(_meta: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Replaces `path` with a hard link to the pool's copy of its content,
+/// adding it to the pool if there's none yet. Does nothing unless
+/// `[cache] dedupe` is on.
+pub async fn share(path: &Path, record: &FileRecord) -> io::Result<()> {
+    if !config::get().cache.dedupe {
+        return Ok(());
+    }
+    let blob = blob_path(&record.sha256);
+    if let Some(parent) = blob.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    // Concurrent downloads of the same track race for the blob, and gc may
+    // remove it in between; a couple of rounds settles either.
+    for _ in 0..3 {
+        match tokio::fs::hard_link(path, &blob).await {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+        let (ours, theirs) = (tokio::fs::metadata(path).await?, tokio::fs::metadata(&blob).await);
+        let theirs = match theirs {
+            Ok(theirs) => theirs,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if file_id(&ours).is_some() && file_id(&ours) == file_id(&theirs) {
+            return Ok(());
+        }
+        // A damaged blob is dropped rather than handed to another entry;
+        // entries still linked to it keep their copy and fail verification.
+        if manifest::file_record(&blob).await?.sha256 != record.sha256 {
+            tracing::warn!("replacing damaged blob {}", blob.display());
+            remove_blob(&blob).await?;
+            continue;
+        }
+        let temp = temp_path(path);
+        match tokio::fs::hard_link(&blob, &temp).await {
+            Ok(()) => return tokio::fs::rename(&temp, path).await,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    tracing::warn!("couldn't share {} with the blob pool", path.display());
+    Ok(())
+}
+
+/// Gives `path` its own copy if it's linked from elsewhere, so it can be
+/// changed in place without touching other entries.
+pub async fn unshare(path: &Path) -> io::Result<()> {
+    if links(&tokio::fs::metadata(path).await?) <= 1 {
+        return Ok(());
+    }
+    let temp = temp_path(path);
+    tokio::fs::copy(path, &temp).await?;
+    tokio::fs::rename(&temp, path).await
+}
+
+async fn remove_blob(blob: &Path) -> io::Result<()> {
+    match tokio::fs::remove_file(blob).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Removes blobs no entry links to any more and returns how many there were.
+pub async fn prune() -> io::Result<u64> {
+    // Without link counts every blob would look unused.
+    if !cfg!(unix) {
+        return Ok(0);
+    }
+    let mut pruned = 0;
+    let mut shards = match tokio::fs::read_dir(CACHEDIR.join(BLOB_DIR)).await {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    while let Some(shard) = shards.next_entry().await? {
+        if !shard.file_type().await?.is_dir() {
+            continue;
+        }
+        let mut blobs = tokio::fs::read_dir(shard.path()).await?;
+        while let Some(blob) = blobs.next_entry().await? {
+            let meta = blob.metadata().await?;
+            if meta.is_file() && links(&meta) == 1 {
+                remove_blob(&blob.path()).await?;
+                pruned += 1;
+            }
+        }
+        gc::remove_if_empty(&shard.path()).await?;
+    }
+    Ok(pruned)
+}
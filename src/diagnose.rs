@@ -0,0 +1,226 @@
+use std::time::{Duration, Instant};
+
+use reqwest::{header::RANGE, tls::TlsInfo, Client, Response, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::{net::TcpStream, time::timeout};
+
+use crate::zvuk;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(20);
+/// Bytes requested from the CDN for the throughput sample.
+const SAMPLE_BYTES: u64 = 1024 * 1024;
+
+#[derive(Deserialize, Default)]
+pub struct DiagnoseRequest {
+    /// Track whose stream is used for the CDN check; skipped if absent.
+    pub track_id: Option<String>,
+    pub auth_cookie: Option<String>,
+    /// Named proxy from `[proxies]`; `TRI_ZVUK_PROXY` if absent.
+    pub proxy: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Tls {
+    /// SHA-256 of the peer's DER certificate.
+    pub peer_certificate_sha256: Option<String>,
+}
+
+#[derive(Serialize, Default)]
+pub struct Check {
+    pub ok: bool,
+    pub latency_ms: Option<u128>,
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<Tls>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct CdnCheck {
+    #[serde(flatten)]
+    pub check: Check,
+    pub host: Option<String>,
+    pub bytes: u64,
+    pub throughput_kbps: Option<u64>,
+    /// Whether the CDN honoured the `Range` request (`206`).
+    pub ranges: bool,
+}
+
+#[derive(Serialize)]
+pub struct ProxyCheck {
+    /// Proxy URL with credentials removed.
+    pub url: String,
+    #[serde(flatten)]
+    pub check: Check,
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub ok: bool,
+    pub proxy: Option<ProxyCheck>,
+    pub graphql: Check,
+    pub cdn: Option<CdnCheck>,
+}
+
+fn redact(url: &Url) -> String {
+    let mut url = url.clone();
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    url.to_string()
+}
+
+fn tls(res: &Response) -> Option<Tls> {
+    let info = res.extensions().get::<TlsInfo>()?;
+    Some(Tls {
+        peer_certificate_sha256: info.peer_certificate().map(|der| hex::encode(Sha256::digest(der))),
+    })
+}
+
+/// Describes a request error, calling out the proxy when one is in the path.
+fn describe(e: &reqwest::Error, proxied: bool) -> String {
+    if proxied && e.is_connect() {
+        format!("couldn't connect through the proxy: {}", e)
+    } else if e.is_timeout() {
+        format!("timed out: {}", e)
+    } else {
+        e.to_string()
+    }
+}
+
+/// Opens a plain TCP connection to the proxy to tell "proxy down" apart from
+/// "upstream unreachable through the proxy".
+async fn check_proxy(url: &Url) -> Check {
+    let Some(host) = url.host_str() else {
+        return Check { error: Some("proxy URL has no host".to_string()), ..Default::default() };
+    };
+    let port = url.port_or_known_default().unwrap_or(1080);
+    let started = Instant::now();
+    let result = match timeout(CONNECT_TIMEOUT, TcpStream::connect((host, port))).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("no answer within {}s", CONNECT_TIMEOUT.as_secs())),
+    };
+    Check {
+        ok: result.is_ok(),
+        latency_ms: Some(started.elapsed().as_millis()),
+        error: result.err(),
+        ..Default::default()
+    }
+}
+
+async fn check_graphql(client: &Client, proxied: bool) -> Check {
+    let body = json!({ "query": "query diagnose { __typename }", "operationName": "diagnose" });
+    let started = Instant::now();
+    let res = client
+        .post(zvuk::client().graphql_url())
+        .header("content-type", "application/json")
+        .body(body.to_string())
+        .timeout(FETCH_TIMEOUT)
+        .send()
+        .await;
+    let latency_ms = Some(started.elapsed().as_millis());
+    match res {
+        Ok(res) => Check {
+            ok: res.status().is_success(),
+            latency_ms,
+            status: Some(res.status().as_u16()),
+            tls: tls(&res),
+            error: (res.status() == reqwest::StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+                .then(|| "proxy rejected the credentials".to_string()),
+        },
+        Err(e) => Check { latency_ms, error: Some(describe(&e, proxied)), ..Default::default() },
+    }
+}
+
+async fn check_cdn(client: &Client, proxied: bool, track_id: &str, auth_cookie: &str) -> CdnCheck {
+    let mut report = CdnCheck { check: Check::default(), host: None, bytes: 0, throughput_kbps: None, ranges: false };
+    let url = match zvuk::stream_urls(track_id, auth_cookie).await {
+        Ok(urls) => match urls.into_iter().last() {
+            Some(url) => url,
+            None => {
+                report.check.error = Some("no stream URL for this track".to_string());
+                return report;
+            }
+        },
+        Err(e) => {
+            report.check.error = Some(format!("couldn't resolve stream: {}", e));
+            return report;
+        }
+    };
+    report.host = Url::parse(&url).ok().and_then(|u| u.host_str().map(str::to_string));
+
+    let started = Instant::now();
+    let res = client
+        .get(&url)
+        .header(RANGE, format!("bytes=0-{}", SAMPLE_BYTES - 1))
+        .timeout(FETCH_TIMEOUT)
+        .send()
+        .await;
+    let mut res = match res {
+        Ok(res) => res,
+        Err(e) => {
+            report.check.error = Some(describe(&e, proxied));
+            return report;
+        }
+    };
+    report.check.latency_ms = Some(started.elapsed().as_millis());
+    report.check.status = Some(res.status().as_u16());
+    report.check.tls = tls(&res);
+    report.ranges = res.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+
+    // Only the sample is read even if the CDN ignores the range.
+    let transfer = Instant::now();
+    loop {
+        match res.chunk().await {
+            Ok(Some(chunk)) => {
+                report.bytes += chunk.len() as u64;
+                if report.bytes >= SAMPLE_BYTES {
+                    break;
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                report.check.error = Some(describe(&e, proxied));
+                return report;
+            }
+        }
+    }
+    let secs = transfer.elapsed().as_secs_f64();
+    if secs > 0.0 {
+        report.throughput_kbps = Some((report.bytes as f64 * 8.0 / 1000.0 / secs) as u64);
+    }
+    report.check.ok = res.status().is_success();
+    report
+}
+
+/// Runs the proxy, GraphQL and CDN checks in turn.
+pub async fn run(req: DiagnoseRequest) -> Result<Report, String> {
+    zvuk::check_proxy(req.proxy.as_deref())?;
+    let client = zvuk::diagnostic_client(req.proxy.as_deref()).map_err(|e| e.to_string())?;
+    let proxy_url = zvuk::proxy_url(req.proxy.as_deref())
+        .map(|url| Url::parse(&url).map_err(|e| format!("invalid proxy URL: {}", e)))
+        .transpose()?;
+
+    let proxy = match &proxy_url {
+        Some(url) => Some(ProxyCheck { url: redact(url), check: check_proxy(url).await }),
+        None => None,
+    };
+    let proxied = proxy.is_some();
+    let graphql = check_graphql(&client, proxied).await;
+    let cdn = match (&req.track_id, &req.auth_cookie) {
+        (Some(track_id), Some(auth_cookie)) => {
+            let check = check_cdn(&client, proxied, track_id, auth_cookie);
+            Some(zvuk::with_proxy(req.proxy.clone(), check).await)
+        }
+        _ => None,
+    };
+
+    let ok = proxy.as_ref().is_none_or(|p| p.check.ok)
+        && graphql.ok
+        && cdn.as_ref().is_none_or(|c| c.check.ok);
+    Ok(Report { ok, proxy, graphql, cdn })
+}
@@ -0,0 +1,70 @@
+use std::error::Error;
+
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+
+use crate::{config, jobs::BatchSummary};
+
+/// Failures listed in full; the rest are only counted.
+const MAX_LISTED: usize = 50;
+
+fn body(summary: &BatchSummary) -> String {
+    let failed = summary.total - summary.done - summary.awaiting_credentials - summary.cancelled;
+    let mut body = format!(
+        "Batch \"{}\" finished.\n\n\
+         Jobs:                    {}\n\
+         Succeeded:               {}\n\
+         Failed:                  {}\n\
+         Waiting for credentials: {}\n\
+         Cancelled:               {}\n\
+         Downloaded:              {:.1} MiB\n",
+        summary.label,
+        summary.total,
+        summary.done,
+        failed,
+        summary.awaiting_credentials,
+        summary.cancelled,
+        summary.bytes as f64 / (1024.0 * 1024.0),
+    );
+    if !summary.failures.is_empty() {
+        body.push_str("\nProblems:\n");
+        for job in summary.failures.iter().take(MAX_LISTED) {
+            body.push_str(&format!(
+                "  job {} (track {}, {}): {}\n",
+                job.id,
+                job.track_id,
+                job.state.as_str(),
+                job.error.as_deref().unwrap_or("no error recorded")
+            ));
+        }
+        if summary.failures.len() > MAX_LISTED {
+            body.push_str(&format!("  ... and {} more\n", summary.failures.len() - MAX_LISTED));
+        }
+    }
+    body
+}
+
+/// Mails the summary of a finished batch to the `[email]` recipients.
+pub async fn send(summary: &BatchSummary) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let Some(email) = &config::get().email else {
+        return Ok(());
+    };
+    let mut message = Message::builder()
+        .from(email.from.parse::<Mailbox>()?)
+        .subject(format!(
+            "TRILib-ZVUK: batch \"{}\" finished ({}/{} succeeded)",
+            summary.label, summary.done, summary.total
+        ))
+        .header(ContentType::TEXT_PLAIN);
+    for to in &email.to {
+        message = message.to(to.parse::<Mailbox>()?);
+    }
+    let message = message.body(body(summary))?;
+    AsyncSmtpTransport::<Tokio1Executor>::from_url(&email.smtp_url)?
+        .build()
+        .send(message)
+        .await?;
+    Ok(())
+}
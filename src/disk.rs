@@ -0,0 +1,50 @@
+use std::{error::Error, fmt, io, path::Path};
+
+use crate::{config, CACHEDIR};
+
+/// Bytes this process may still write on the filesystem holding `path`;
+/// `None` where that can't be told.
+#[cfg(unix)]
+pub fn available(path: &Path) -> io::Result<Option<u64>> {
+    let stat = rustix::fs::statvfs(path)?;
+    Ok(Some(stat.f_bavail.saturating_mul(stat.f_frsize)))
+}
+
+#[cfg(not(unix))]
+pub fn available(_path: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+/// Writing would eat into `[cache] min_free_bytes`.
+#[derive(Debug)]
+pub struct StorageFull {
+    pub available: u64,
+    pub needed: u64,
+}
+
+impl fmt::Display for StorageFull {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "not enough free space in the cache: {} bytes available, {} needed with the reserve",
+            self.available, self.needed
+        )
+    }
+}
+
+impl Error for StorageFull {}
+
+/// Fails if writing `incoming` more bytes into the cache would leave less
+/// than the reserve. Passes if free space can't be read, so a filesystem
+/// without `statvfs` doesn't stop downloads.
+pub fn check(incoming: u64) -> Result<(), StorageFull> {
+    let needed = config::get().cache.min_free_bytes.saturating_add(incoming);
+    match available(&CACHEDIR) {
+        Ok(Some(available)) if available < needed => Err(StorageFull { available, needed }),
+        Ok(_) => Ok(()),
+        Err(e) => {
+            tracing::warn!("couldn't read free space of {}: {}", CACHEDIR.display(), e);
+            Ok(())
+        }
+    }
+}
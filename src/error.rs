@@ -0,0 +1,156 @@
+use std::{error::Error, time::Duration};
+
+use axum::{
+    http::{header::RETRY_AFTER, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use crate::failure::{Category, Failure};
+
+/// What went wrong, in terms a client can act on.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// The request itself is wrong; sending it again won't help.
+    InvalidRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    /// Clashes with the current state, like cancelling a finished job.
+    Conflict,
+    /// This service's own rate limits or quotas.
+    RateLimited,
+    /// The Zvuk cookie expired; send a fresh one.
+    AuthExpired,
+    UpstreamRateLimited,
+    /// Zvuk or its CDN failed or couldn't be reached.
+    UpstreamError,
+    StorageFull,
+    Timeout,
+    /// Shutting down, or a dependency isn't ready.
+    Unavailable,
+    Internal,
+}
+
+impl ErrorCode {
+    /// Whether the same request may succeed later without changes.
+    pub fn retryable(self) -> bool {
+        matches!(
+            self,
+            ErrorCode::RateLimited
+                | ErrorCode::UpstreamRateLimited
+                | ErrorCode::UpstreamError
+                | ErrorCode::StorageFull
+                | ErrorCode::Timeout
+                | ErrorCode::Unavailable
+        )
+    }
+
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthorized | ErrorCode::AuthExpired => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::UpstreamRateLimited | ErrorCode::UpstreamError => StatusCode::BAD_GATEWAY,
+            ErrorCode::StorageFull => StatusCode::INSUFFICIENT_STORAGE,
+            ErrorCode::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            ErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The code for an error that only has a status to go on.
+    pub fn for_status(status: StatusCode) -> ErrorCode {
+        match status {
+            StatusCode::UNAUTHORIZED => ErrorCode::Unauthorized,
+            StatusCode::FORBIDDEN => ErrorCode::Forbidden,
+            StatusCode::NOT_FOUND => ErrorCode::NotFound,
+            StatusCode::CONFLICT => ErrorCode::Conflict,
+            StatusCode::TOO_MANY_REQUESTS => ErrorCode::RateLimited,
+            StatusCode::BAD_GATEWAY => ErrorCode::UpstreamError,
+            StatusCode::SERVICE_UNAVAILABLE => ErrorCode::Unavailable,
+            StatusCode::GATEWAY_TIMEOUT => ErrorCode::Timeout,
+            StatusCode::INSUFFICIENT_STORAGE => ErrorCode::StorageFull,
+            s if s.is_client_error() => ErrorCode::InvalidRequest,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+impl From<Category> for ErrorCode {
+    fn from(category: Category) -> Self {
+        match category {
+            Category::AuthExpired => ErrorCode::AuthExpired,
+            Category::NotFound => ErrorCode::NotFound,
+            Category::RateLimited => ErrorCode::UpstreamRateLimited,
+            Category::Upstream | Category::Network | Category::Integrity => ErrorCode::UpstreamError,
+            Category::Storage => ErrorCode::StorageFull,
+            Category::Transcode | Category::Panic | Category::Internal => ErrorCode::Internal,
+        }
+    }
+}
+
+/// The body of every error response:
+/// `{"ok": false, "error", "error_code", "retryable", "retry_after_secs"}`.
+/// `Retry-After` is sent too when `retry_after_secs` is.
+#[derive(Serialize, Debug)]
+pub struct ApiError {
+    #[serde(skip)]
+    status: StatusCode,
+    ok: bool,
+    error: String,
+    error_code: ErrorCode,
+    retryable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_secs: Option<u64>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, error: impl Into<String>) -> Self {
+        let error_code = ErrorCode::for_status(status);
+        ApiError {
+            status,
+            ok: false,
+            error: error.into(),
+            error_code,
+            retryable: error_code.retryable(),
+            retry_after_secs: None,
+        }
+    }
+
+    /// An error with `code`'s usual status.
+    pub fn coded(code: ErrorCode, error: impl Into<String>) -> Self {
+        ApiError { error_code: code, retryable: code.retryable(), ..ApiError::new(code.status(), error) }
+    }
+
+    /// A call to Zvuk that failed; the code follows what the error chain
+    /// says about the cause.
+    pub fn upstream(e: &(dyn Error + 'static)) -> Self {
+        let failure = Failure::classify(e);
+        let code = match ErrorCode::from(failure.category) {
+            ErrorCode::Internal => ErrorCode::UpstreamError,
+            code => code,
+        };
+        ApiError::coded(code, failure.message)
+    }
+
+    pub fn retry_after(mut self, wait: Duration) -> Self {
+        self.retry_after_secs = Some(wait.as_secs().max(1));
+        self
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status, Json(&self)).into_response();
+        if let Some(secs) = self.retry_after_secs {
+            response.headers_mut().insert(RETRY_AFTER, secs.into());
+        }
+        response
+    }
+}
@@ -0,0 +1,199 @@
+use std::{error::Error, io::ErrorKind};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{accounts, disk, zvuk};
+
+/// Broad cause of a failed job, used to suggest a fix.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    AuthExpired,
+    NotFound,
+    RateLimited,
+    Upstream,
+    Network,
+    Integrity,
+    Transcode,
+    Storage,
+    Panic,
+    Internal,
+}
+
+impl Category {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Category::AuthExpired => "auth_expired",
+            Category::NotFound => "not_found",
+            Category::RateLimited => "rate_limited",
+            Category::Upstream => "upstream",
+            Category::Network => "network",
+            Category::Integrity => "integrity",
+            Category::Transcode => "transcode",
+            Category::Storage => "storage",
+            Category::Panic => "panic",
+            Category::Internal => "internal",
+        }
+    }
+
+    /// Failures that may well go away on their own.
+    pub fn retriable(self) -> bool {
+        matches!(self, Category::Network | Category::Upstream | Category::RateLimited | Category::Integrity)
+    }
+
+    pub fn parse(s: &str) -> Option<Category> {
+        serde_json::from_value(serde_json::Value::String(s.to_string())).ok()
+    }
+
+    pub fn hint(self) -> &'static str {
+        match self {
+            Category::AuthExpired => {
+                "cookie expired — refresh the Zvuk session and send it to POST /auth/validate to resume parked jobs"
+            }
+            Category::NotFound => {
+                "Zvuk doesn't offer this track (removed or region-locked); check it with POST /diff or try another proxy"
+            }
+            Category::RateLimited => "Zvuk is rate limiting this account; retry later or spread downloads out",
+            Category::Upstream => "Zvuk returned a server error; retry later",
+            Category::Network => {
+                "couldn't reach Zvuk or its CDN; run POST /admin/diagnose and check the proxy settings"
+            }
+            Category::Integrity => "the CDN transfer was incomplete or corrupted; resubmit the job",
+            Category::Transcode => "ffmpeg failed; check TRI_ZVUK_FFMPEG and that it supports the requested codec",
+            Category::Storage => "the cache's filesystem is full; free space in TRI_CACHE or lower min_free_bytes",
+            Category::Panic => "internal error; please report it with the job's request_id",
+            Category::Internal => "internal error; check the service logs for this job's request_id",
+        }
+    }
+}
+
+fn from_status(status: reqwest::StatusCode) -> Category {
+    match status.as_u16() {
+        401 | 403 => Category::AuthExpired,
+        404 | 410 => Category::NotFound,
+        429 => Category::RateLimited,
+        _ => Category::Upstream,
+    }
+}
+
+/// Why a job attempt failed, with enough detail to explain it later.
+#[derive(Serialize, Clone, Debug)]
+pub struct Failure {
+    pub category: Category,
+    pub message: String,
+    /// `message` followed by each underlying cause.
+    pub chain: Vec<String>,
+    pub upstream_status: Option<u16>,
+}
+
+impl Failure {
+    pub fn new(category: Category, message: impl Into<String>) -> Self {
+        let message = message.into();
+        Failure { category, chain: vec![message.clone()], message, upstream_status: None }
+    }
+
+    /// Walks the error chain, picking the most specific category and any
+    /// upstream HTTP status on the way.
+    pub fn classify(e: &(dyn Error + 'static)) -> Self {
+        let mut category = None;
+        let mut upstream_status = None;
+        let mut io_error = false;
+        let mut chain = Vec::new();
+        let mut cause = Some(e);
+        while let Some(err) = cause {
+            chain.push(err.to_string());
+            let found = if let Some(auth) = err.downcast_ref::<zvuk::AuthExpired>() {
+                upstream_status = upstream_status.or(auth.status.map(|s| s.as_u16()));
+                Some(Category::AuthExpired)
+            } else if let Some(exhausted) = err.downcast_ref::<accounts::PoolExhausted>() {
+                Some(exhausted.category)
+            } else if err.is::<disk::StorageFull>() {
+                Some(Category::Storage)
+            } else if err.is::<zvuk::TrackNotFound>() {
+                Some(Category::NotFound)
+            } else if err.is::<zvuk::GraphQLError>() {
+                let text = err.to_string().to_ascii_lowercase();
+                Some(if text.contains("not found") { Category::NotFound } else { Category::Upstream })
+            } else if let Some(zvuk::ApiError(status)) = err.downcast_ref::<zvuk::ApiError>() {
+                upstream_status = upstream_status.or(Some(status.as_u16()));
+                Some(from_status(*status))
+            } else if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+                match err.status() {
+                    Some(status) => {
+                        upstream_status = upstream_status.or(Some(status.as_u16()));
+                        Some(from_status(status))
+                    }
+                    None if err.is_decode() => Some(Category::Upstream),
+                    None => Some(Category::Network),
+                }
+            } else if let Some(err) = err.downcast_ref::<std::io::Error>() {
+                // Only a full disk is worth retrying; permissions, missing
+                // paths and the like need someone to look at the cache. An
+                // io::Error may also just wrap a cause that says more.
+                match err.kind() {
+                    ErrorKind::StorageFull | ErrorKind::QuotaExceeded => Some(Category::Storage),
+                    _ => {
+                        io_error = true;
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            category = category.or(found);
+            cause = err.source();
+        }
+
+        let message = e.to_string();
+        let category = category.unwrap_or_else(|| {
+            let text = message.to_ascii_lowercase();
+            if io_error {
+                Category::Internal
+            } else if text.contains("truncated download") || text.contains("doesn't match the downloaded bytes") {
+                Category::Integrity
+            } else if text.contains("ffmpeg") || text.contains("bitrate") {
+                Category::Transcode
+            } else if text.contains("not found") {
+                Category::NotFound
+            } else {
+                Category::Internal
+            }
+        });
+        Failure { category, message, chain, upstream_status }
+    }
+}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Self {
+        Failure::new(Category::Internal, message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::error::ErrorCode;
+
+    fn code(e: &(dyn Error + 'static)) -> ErrorCode {
+        ErrorCode::from(Failure::classify(e).category)
+    }
+
+    #[test]
+    fn full_disks_are_storage_errors() {
+        assert_eq!(code(&io::Error::from(ErrorKind::StorageFull)), ErrorCode::StorageFull);
+        assert_eq!(code(&io::Error::from(ErrorKind::QuotaExceeded)), ErrorCode::StorageFull);
+        assert_eq!(code(&disk::StorageFull { available: 1, needed: 2 }), ErrorCode::StorageFull);
+    }
+
+    #[test]
+    fn other_io_errors_are_internal() {
+        for kind in [ErrorKind::PermissionDenied, ErrorKind::NotFound, ErrorKind::Other] {
+            let failure = Failure::classify(&io::Error::from(kind));
+            assert_eq!(failure.category, Category::Internal, "{:?}", kind);
+            assert_ne!(ErrorCode::from(failure.category), ErrorCode::StorageFull);
+            assert!(!ErrorCode::from(failure.category).retryable());
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::{collections::HashSet, io, path::Path, time::Duration};
+
+use serde::Serialize;
+
+use crate::{config, dedupe, jobs, manifest, source, CACHEDIR};
+
+#[derive(Serialize, Default)]
+pub struct Report {
+    /// Hashes whose empty source directory was removed.
+    pub removed: Vec<String>,
+    /// Deduplicated files no entry used any more.
+    pub blobs_removed: u64,
+}
+
+/// Removes `dir` if it's an empty directory; `false` if it has contents or
+/// doesn't exist.
+pub async fn remove_if_empty(dir: &Path) -> io::Result<bool> {
+    match tokio::fs::remove_dir(dir).await {
+        Ok(()) => Ok(true),
+        Err(e) if matches!(e.kind(), io::ErrorKind::DirectoryNotEmpty | io::ErrorKind::NotFound) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Removes empty `{hash}/<source>` directories, then `{hash}` itself if nothing
+/// else (e.g. another provider) lives in it, and unused blobs of
+/// `[cache] dedupe`. Entries of running jobs are left alone since they may
+/// not have written anything yet.
+pub async fn sweep() -> io::Result<Report> {
+    let busy = jobs::running_hashes();
+    let mut report = Report::default();
+    let mut dir = match tokio::fs::read_dir(&*CACHEDIR).await {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(report),
+        Err(e) => return Err(e),
+    };
+    while let Some(file) = dir.next_entry().await? {
+        let Some(hash) = file.file_name().to_str().map(String::from) else {
+            continue;
+        };
+        if !file.file_type().await?.is_dir() || busy.contains(&hash) {
+            continue;
+        }
+        if remove_if_empty(&file.path().join(source())).await? {
+            report.removed.push(hash);
+        }
+        remove_if_empty(&file.path()).await?;
+    }
+    report.blobs_removed = dedupe::prune().await?;
+    Ok(report)
+}
+
+#[derive(Serialize, Default)]
+pub struct Usage {
+    /// Entries of this source with a manifest.
+    pub entries: u64,
+    /// Size of every file in those entries, counting deduplicated ones once.
+    pub bytes: u64,
+}
+
+/// Walks the cache and adds up this source's entries.
+pub async fn usage() -> io::Result<Usage> {
+    let mut usage = Usage::default();
+    // Deduplicated files are linked from several entries but stored once.
+    let mut seen = HashSet::new();
+    let mut dir = match tokio::fs::read_dir(&*CACHEDIR).await {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(usage),
+        Err(e) => return Err(e),
+    };
+    while let Some(hash) = dir.next_entry().await? {
+        if !hash.file_type().await?.is_dir() {
+            continue;
+        }
+        let entry = hash.path().join(source());
+        if !tokio::fs::try_exists(entry.join(manifest::MANIFEST_FILE)).await? {
+            continue;
+        }
+        usage.entries += 1;
+        let mut files = tokio::fs::read_dir(&entry).await?;
+        while let Some(file) = files.next_entry().await? {
+            let meta = file.metadata().await?;
+            if meta.is_file() && dedupe::file_id(&meta).is_none_or(|id| seen.insert(id)) {
+                usage.bytes += meta.len();
+            }
+        }
+    }
+    Ok(usage)
+}
+
+/// Sweeps every `[cache] gc_interval_secs`; disabled if 0.
+pub async fn run_periodically() {
+    let interval = config::get().cache.gc_interval_secs;
+    if interval == 0 {
+        return;
+    }
+    loop {
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+        match sweep().await {
+            Ok(report) if !report.removed.is_empty() || report.blobs_removed > 0 => tracing::info!(
+                "gc removed {} empty entries and {} unused blobs",
+                report.removed.len(),
+                report.blobs_removed
+            ),
+            Ok(_) => {}
+            Err(e) => tracing::warn!("gc failed: {}", e),
+        }
+    }
+}
@@ -0,0 +1,415 @@
+use std::{net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
+
+use futures_util::Stream;
+use serde::de::DeserializeOwned;
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast::error::RecvError, mpsc},
+};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status};
+
+use crate::{
+    accounts, auth, config, entry_dir,
+    jobs::{self, JobState},
+    limits::{ClientLimiter, GroupLimiter},
+    manifest, tenant, tls, transcode, validate::Validate, zvuk, DownloadZVUK,
+};
+
+mod proto {
+    include!(concat!(env!("OUT_DIR"), "/trilib.zvuk.ZvukService.rs"));
+}
+
+use proto::zvuk_service_server::{ZvukService, ZvukServiceServer};
+
+// Messages of proto/trilib_zvuk.proto.
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DownloadRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub hash: String,
+    #[prost(string, tag = "3")]
+    pub auth_cookie: String,
+    #[prost(string, tag = "4")]
+    pub proxy: String,
+    #[prost(bool, tag = "5")]
+    pub include_lyrics: bool,
+    #[prost(bool, tag = "6")]
+    pub normalize: bool,
+    #[prost(string, tag = "7")]
+    pub label: String,
+    #[prost(string, tag = "8")]
+    pub priority: String,
+    #[prost(string, tag = "9")]
+    pub transcode_codec: String,
+    #[prost(uint32, tag = "10")]
+    pub transcode_bitrate: u32,
+    #[prost(string, tag = "11")]
+    pub explicit_policy: String,
+    #[prost(string, tag = "12")]
+    pub idempotency_key: String,
+    #[prost(uint32, tag = "13")]
+    pub max_kbps: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FileRecord {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(uint64, tag = "2")]
+    pub size: u64,
+    #[prost(string, tag = "3")]
+    pub sha256: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DownloadProgress {
+    #[prost(int64, tag = "1")]
+    pub job_id: i64,
+    #[prost(string, tag = "2")]
+    pub state: String,
+    #[prost(string, tag = "3")]
+    pub error: String,
+    #[prost(bool, tag = "4")]
+    pub replayed: bool,
+    #[prost(message, repeated, tag = "5")]
+    pub files: Vec<FileRecord>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetJobStatusRequest {
+    #[prost(int64, tag = "1")]
+    pub id: i64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Job {
+    #[prost(int64, tag = "1")]
+    pub id: i64,
+    #[prost(string, tag = "2")]
+    pub track_id: String,
+    #[prost(string, tag = "3")]
+    pub hash: String,
+    #[prost(string, tag = "4")]
+    pub state: String,
+    #[prost(string, tag = "5")]
+    pub error: String,
+    #[prost(int64, tag = "6")]
+    pub created_at: i64,
+    #[prost(int64, tag = "7")]
+    pub updated_at: i64,
+    #[prost(int64, optional, tag = "8")]
+    pub started_at: Option<i64>,
+    #[prost(int64, optional, tag = "9")]
+    pub finished_at: Option<i64>,
+    #[prost(string, tag = "10")]
+    pub label: String,
+    #[prost(string, tag = "11")]
+    pub priority: String,
+    #[prost(int64, optional, tag = "12")]
+    pub not_before: Option<i64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetMetadataRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub auth_cookie: String,
+    #[prost(string, tag = "3")]
+    pub proxy: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TrackMetadata {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub title: String,
+    #[prost(string, repeated, tag = "3")]
+    pub artists: Vec<String>,
+    #[prost(uint64, optional, tag = "4")]
+    pub duration: Option<u64>,
+    #[prost(bool, optional, tag = "5")]
+    pub explicit: Option<bool>,
+    #[prost(string, tag = "6")]
+    pub isrc: String,
+    #[prost(string, tag = "7")]
+    pub release_id: String,
+    #[prost(string, tag = "8")]
+    pub release_title: String,
+    #[prost(string, tag = "9")]
+    pub release_date: String,
+}
+
+impl From<jobs::Job> for Job {
+    fn from(job: jobs::Job) -> Self {
+        Job {
+            id: job.id,
+            track_id: job.track_id,
+            hash: job.hash,
+            state: job.state.as_str().to_string(),
+            error: job.error.unwrap_or_default(),
+            created_at: job.created_at,
+            updated_at: job.updated_at,
+            started_at: job.started_at,
+            finished_at: job.finished_at,
+            label: job.label.unwrap_or_default(),
+            priority: job.priority.as_str().to_string(),
+            not_before: job.not_before,
+        }
+    }
+}
+
+impl From<manifest::FileRecord> for FileRecord {
+    fn from(file: manifest::FileRecord) -> Self {
+        FileRecord { name: file.name, size: file.size, sha256: file.sha256 }
+    }
+}
+
+/// Same checks as the HTTP routes of the matching group.
+fn authenticate<T>(request: &Request<T>, needed: auth::Role) -> Result<Option<(auth::Principal, auth::ClientId)>, Status> {
+    auth::authenticate(&request.metadata().clone().into_headers(), needed).map_err(|denied| match denied {
+        auth::Denied::Unauthenticated => Status::unauthenticated("missing or unknown API key"),
+        auth::Denied::Forbidden(error) => Status::permission_denied(error),
+    })
+}
+
+/// The caller for `[clients] rate_per_minute`: its key, like over HTTP, or
+/// else its IP.
+fn client_id<T>(request: &Request<T>, identity: Option<&auth::ClientId>) -> auth::ClientId {
+    match identity {
+        Some(client) => client.clone(),
+        None => {
+            let ip = request.remote_addr().map(|addr| addr.ip().to_string());
+            auth::ClientId(format!("ip:{}", ip.as_deref().unwrap_or("unknown")))
+        }
+    }
+}
+
+fn rate_limited(retry_after: Duration, error: &str) -> Status {
+    Status::resource_exhausted(format!("{}; retry in {}s", error, retry_after.as_secs().max(1)))
+}
+
+/// Empty means unset; otherwise the value as its JSON spelling would parse.
+fn parse<T: DeserializeOwned>(field: &str, value: &str) -> Result<Option<T>, Status> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    serde_json::from_value(serde_json::Value::String(value.to_string()))
+        .map(Some)
+        .map_err(|_| Status::invalid_argument(format!("invalid {} {:?}", field, value)))
+}
+
+fn non_empty(s: String) -> Option<String> {
+    Some(s).filter(|s| !s.is_empty())
+}
+
+fn submit_status(e: jobs::SubmitError) -> Status {
+    match e {
+        jobs::SubmitError::ShuttingDown => Status::unavailable(e.to_string()),
+        jobs::SubmitError::TooManyJobs
+        | jobs::SubmitError::QuotaExceeded { .. }
+        | jobs::SubmitError::StorageFull(_) => {
+            Status::resource_exhausted(e.to_string())
+        }
+        jobs::SubmitError::KeyReused => Status::failed_precondition(e.to_string()),
+        jobs::SubmitError::Store(_) => Status::internal(e.to_string()),
+    }
+}
+
+/// What a job looks like to the client: its state and, once done, the files.
+async fn progress(job: &jobs::Job, replayed: bool) -> DownloadProgress {
+    let files = match job.state {
+        JobState::Done => manifest::read(&entry_dir(&job.hash))
+            .await
+            .map(|m| m.files.into_iter().map(FileRecord::from).collect())
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    };
+    DownloadProgress {
+        job_id: job.id,
+        state: job.state.as_str().to_string(),
+        error: job.error.clone().unwrap_or_default(),
+        replayed,
+        files,
+    }
+}
+
+/// Sends the job's state, and again whenever it changes, until it settles or
+/// the client goes away. Events only say the job changed: they're read from
+/// the store when published, so they can arrive out of order.
+async fn follow(
+    id: i64,
+    replayed: bool,
+    mut events: tokio::sync::broadcast::Receiver<jobs::Job>,
+    tx: mpsc::Sender<Result<DownloadProgress, Status>>,
+) {
+    let mut last = None;
+    loop {
+        let job = match jobs::queue().store.get(id) {
+            Ok(Some(job)) => job,
+            Ok(None) => return,
+            Err(e) => {
+                let _ = tx.send(Err(Status::internal(e.to_string()))).await;
+                return;
+            }
+        };
+        if last != Some(job.state) {
+            last = Some(job.state);
+            if tx.send(Ok(progress(&job, replayed).await)).await.is_err() || job.state.is_settled() {
+                return;
+            }
+        }
+        loop {
+            let event = tokio::select! {
+                event = events.recv() => event,
+                _ = tx.closed() => return,
+            };
+            match event {
+                Ok(event) if event.id == id => break,
+                Ok(_) => {}
+                Err(RecvError::Lagged(_)) => break,
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+/// The rate limits gRPC calls share with the HTTP routes.
+struct Server {
+    clients: Arc<ClientLimiter>,
+    /// The `[routes.download]` group's.
+    download: Arc<GroupLimiter>,
+}
+
+impl Server {
+    fn limit_client<T>(&self, request: &Request<T>, identity: Option<&auth::ClientId>) -> Result<(), Status> {
+        self.clients
+            .check(&client_id(request, identity))
+            .map_err(|retry_after| rate_limited(retry_after, "client rate limit exceeded"))
+    }
+}
+
+type ProgressStream = Pin<Box<dyn Stream<Item = Result<DownloadProgress, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl ZvukService for Server {
+    type DownloadStream = ProgressStream;
+
+    async fn download(&self, request: Request<DownloadRequest>) -> Result<Response<ProgressStream>, Status> {
+        let identity = authenticate(&request, auth::Role::Submit)?;
+        self.limit_client(&request, identity.as_ref().map(|(_, c)| c))?;
+        self.download.check().map_err(|retry_after| rate_limited(retry_after, "rate limit exceeded"))?;
+        let principal = identity.as_ref().map(|(p, _)| p);
+        let request_id = request.metadata().get("x-request-id").and_then(|v| v.to_str().ok()).map(String::from);
+        let req = request.into_inner();
+        if principal.is_some_and(|p| !p.may_download(&req.id)) {
+            return Err(Status::permission_denied("token doesn't cover this track"));
+        }
+        let hash = tenant::scheme(principal).canonicalize(&req.hash).map_err(Status::invalid_argument)?;
+        let proxy = non_empty(req.proxy);
+        zvuk::check_proxy(proxy.as_deref()).map_err(Status::invalid_argument)?;
+        accounts::check_cookie(&req.auth_cookie).map_err(Status::invalid_argument)?;
+        let transcode = parse::<transcode::Codec>("transcode_codec", &req.transcode_codec)?.map(|codec| {
+            transcode::Transcode { codec, bitrate: Some(req.transcode_bitrate).filter(|&b| b > 0) }
+        });
+        let payload = DownloadZVUK {
+            id: req.id,
+            hash,
+            auth_cookie: req.auth_cookie,
+            transcode,
+            proxy,
+            include_lyrics: req.include_lyrics,
+            normalize: req.normalize,
+            label: non_empty(req.label),
+            explicit_policy: parse::<config::ExplicitPolicy>("explicit_policy", &req.explicit_policy)?,
+            checksums: false,
+            priority: Some(parse("priority", &req.priority)?.unwrap_or(jobs::Priority::High)),
+            not_before: None,
+            idempotency_key: non_empty(req.idempotency_key),
+            max_kbps: Some(req.max_kbps).filter(|&kbps| kbps > 0),
+        };
+        payload.validate().map_err(Status::invalid_argument)?;
+        let owner = jobs::Owner {
+            client: identity.as_ref().map(|(_, c)| c.0.as_str()),
+            tenant: principal.and_then(|p| p.tenant()),
+            request_id: request_id.as_deref(),
+        };
+
+        // Subscribe first so no state change between submit and follow is lost.
+        let events = jobs::queue().subscribe();
+        let submitted = jobs::queue()
+            .submit(&payload, &owner, payload.idempotency_key.as_deref())
+            .map_err(submit_status)?;
+        let (tx, rx) = mpsc::channel(8);
+        tokio::spawn(async move {
+            let replayed = matches!(submitted, jobs::Submitted::Replayed(_));
+            // Like /dl, a call that goes away takes the job it started with
+            // it; cancelling a settled job is a no-op.
+            let _guard = (!replayed).then(|| jobs::CancelGuard::new(submitted.id(), None));
+            follow(submitted.id(), replayed, events, tx).await;
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    async fn get_job_status(&self, request: Request<GetJobStatusRequest>) -> Result<Response<Job>, Status> {
+        let identity = authenticate(&request, auth::Role::ReadOnly)?;
+        self.limit_client(&request, identity.as_ref().map(|(_, c)| c))?;
+        let principal = identity.map(|(principal, _)| principal);
+        match jobs::queue().store.get(request.get_ref().id) {
+            Ok(Some(job)) if principal.as_ref().is_none_or(|p| p.may_see(&job)) => Ok(Response::new(job.into())),
+            Ok(_) => Err(Status::not_found("no such job")),
+            Err(e) => Err(Status::internal(e.to_string())),
+        }
+    }
+
+    async fn get_metadata(&self, request: Request<GetMetadataRequest>) -> Result<Response<TrackMetadata>, Status> {
+        let identity = authenticate(&request, auth::Role::ReadOnly)?;
+        self.limit_client(&request, identity.as_ref().map(|(_, c)| c))?;
+        let req = request.into_inner();
+        let proxy = non_empty(req.proxy);
+        zvuk::check_proxy(proxy.as_deref()).map_err(Status::invalid_argument)?;
+        let cookie = non_empty(req.auth_cookie);
+        let lookup = async { zvuk::track_meta(&req.id, cookie.as_deref()).await.map_err(|e| e.to_string()) };
+        let meta = zvuk::with_proxy(proxy, lookup).await.map_err(Status::unavailable)?;
+        let release = meta.release.as_ref();
+        Ok(Response::new(TrackMetadata {
+            artists: meta.artists.iter().map(|a| a.title.clone()).collect(),
+            duration: meta.duration,
+            explicit: meta.explicit,
+            isrc: meta.isrc.clone().unwrap_or_default(),
+            release_id: release.map(|r| r.id.clone()).unwrap_or_default(),
+            release_title: release.map(|r| r.title.clone()).unwrap_or_default(),
+            release_date: release.and_then(|r| r.date.clone()).unwrap_or_default(),
+            title: meta.title.clone(),
+            id: meta.id,
+        }))
+    }
+}
+
+/// Serves the gRPC API on `port` until `shutdown` completes, over TLS with
+/// the HTTP port's certificate if `[tls]` is set.
+pub async fn serve(
+    port: u16,
+    clients: Arc<ClientLimiter>,
+    download: Arc<GroupLimiter>,
+    shutdown: impl Future<Output = ()>,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port))).await?;
+    let server = tonic::transport::Server::builder().add_service(ZvukServiceServer::new(Server { clients, download }));
+    match &config::get().tls {
+        Some(tls) => {
+            let listener = tls::listen(listener, tls, b"h2").map_err(std::io::Error::other)?;
+            tracing::info!("serving gRPC over TLS on port {}", port);
+            server.serve_with_incoming_shutdown(listener.into_stream(), shutdown).await
+        }
+        None => {
+            tracing::info!("serving gRPC on port {}", port);
+            server.serve_with_incoming_shutdown(TcpListenerStream::new(listener), shutdown).await
+        }
+    }
+    .map_err(std::io::Error::other)
+}
@@ -0,0 +1,77 @@
+use std::time::{Duration, Instant};
+
+use axum::{extract::Query, response::IntoResponse, Json};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::time::timeout;
+
+use crate::{jobs, zvuk, CACHEDIR};
+
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize)]
+struct Component {
+    ok: bool,
+    latency_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+async fn check<F, E>(probe: F) -> Component
+where
+    F: Future<Output = Result<(), E>>,
+    E: ToString,
+{
+    let started = Instant::now();
+    let result = probe.await;
+    Component {
+        ok: result.is_ok(),
+        latency_ms: started.elapsed().as_millis(),
+        error: result.err().map(|e| e.to_string()),
+    }
+}
+
+async fn cache_writable() -> std::io::Result<()> {
+    let probe = CACHEDIR.join(format!(".readyz-{}", std::process::id()));
+    tokio::fs::write(&probe, b"ok").await?;
+    tokio::fs::remove_file(&probe).await
+}
+
+async fn upstream_reachable() -> Result<(), String> {
+    let probe = zvuk::graphql::<Value>("health", "query health { __typename }", json!({}), None);
+    match timeout(UPSTREAM_TIMEOUT, probe).await {
+        Ok(result) => result.map(|_| ()).map_err(|e| e.to_string()),
+        Err(_) => Err(format!("no answer within {}s", UPSTREAM_TIMEOUT.as_secs())),
+    }
+}
+
+/// Liveness: the process is up and serving.
+pub async fn healthz() -> impl IntoResponse {
+    Json(json!({ "ok": true }))
+}
+
+#[derive(Deserialize)]
+pub struct ReadyParams {
+    /// Also check that Zvuk's GraphQL endpoint answers.
+    #[serde(default)]
+    upstream: bool,
+}
+
+/// Readiness: the cache is writable and the job database answers, plus Zvuk
+/// itself with `?upstream=true`. `503` if any component fails.
+pub async fn readyz(Query(params): Query<ReadyParams>) -> impl IntoResponse {
+    let mut components = serde_json::Map::new();
+    let mut insert = |name: &str, component: Component| {
+        let ok = component.ok;
+        components.insert(name.to_string(), json!(component));
+        ok
+    };
+    let mut ready = insert("cache", check(cache_writable()).await);
+    ready &= insert("jobs", check(async { jobs::queue().store.ping() }).await);
+    if params.upstream {
+        ready &= insert("upstream", check(upstream_reachable()).await);
+    }
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(json!({ "ok": ready, "components": components })))
+}
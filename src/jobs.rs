@@ -0,0 +1,1616 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use once_cell::sync::OnceCell;
+use tracing::Instrument;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{broadcast, oneshot, Notify},
+    task::AbortHandle,
+    time::timeout,
+};
+
+use crate::{
+    cleanup_incomplete, collection, config, db, digest, disk,
+    failure::{Category, Failure},
+    limits::Deadline,
+    entry_dir, manifest, save_by_id, throttle, unix_now, zvuk, DownloadZVUK, EntrySnapshot,
+};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+    /// Failed because Zvuk rejected the session; resumed by `/auth/validate`.
+    AwaitingCredentials,
+    /// Stopped by `DELETE /jobs/{id}`.
+    Cancelled,
+}
+
+impl JobState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Done => "done",
+            JobState::Failed => "failed",
+            JobState::AwaitingCredentials => "awaiting_credentials",
+            JobState::Cancelled => "cancelled",
+        }
+    }
+
+    /// Nothing will happen to the job without someone acting on it.
+    pub fn is_settled(self) -> bool {
+        !matches!(self, JobState::Queued | JobState::Running)
+    }
+
+    fn parse(s: &str) -> Option<JobState> {
+        match s {
+            "queued" => Some(JobState::Queued),
+            "running" => Some(JobState::Running),
+            "done" => Some(JobState::Done),
+            "failed" => Some(JobState::Failed),
+            "awaiting_credentials" => Some(JobState::AwaitingCredentials),
+            "cancelled" => Some(JobState::Cancelled),
+            _ => None,
+        }
+    }
+}
+
+/// Which queued jobs workers pick first; within a priority, jobs run in
+/// submission order.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Interactive requests; `/dl` if not given.
+    High,
+    /// `POST /jobs` if not given.
+    #[default]
+    Normal,
+    /// Bulk backfills; `/dl/artist` if not given.
+    Low,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Priority> {
+        Priority::ALL.into_iter().find(|p| p.as_str() == s)
+    }
+
+    fn rank(self) -> usize {
+        self as usize
+    }
+}
+
+/// A job as reported by the API; the stored payload (with its cookie) is never exposed.
+#[derive(Serialize, Clone, Debug)]
+pub struct Job {
+    pub id: i64,
+    pub track_id: String,
+    pub hash: String,
+    pub state: JobState,
+    pub error: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+    pub label: Option<String>,
+    pub tenant: Option<String>,
+    /// `X-Request-Id` of the request that submitted the job.
+    pub request_id: Option<String>,
+    pub priority: Priority,
+    /// Unix timestamp before which the job isn't started.
+    pub not_before: Option<i64>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct JobFilter {
+    pub state: Option<JobState>,
+    pub label: Option<String>,
+    pub tenant: Option<String>,
+    pub track_id: Option<String>,
+    pub hash: Option<String>,
+    pub priority: Option<Priority>,
+    /// Only jobs created at or after this unix timestamp.
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS jobs (
+    id          INTEGER PRIMARY KEY AUTOINCREMENT,
+    track_id    TEXT NOT NULL,
+    hash        TEXT NOT NULL,
+    params      TEXT NOT NULL,
+    state       TEXT NOT NULL,
+    error       TEXT,
+    created_at  INTEGER NOT NULL,
+    updated_at  INTEGER NOT NULL,
+    started_at  INTEGER,
+    finished_at INTEGER
+);
+CREATE INDEX IF NOT EXISTS jobs_state ON jobs (state);
+CREATE INDEX IF NOT EXISTS jobs_hash ON jobs (hash);
+CREATE TABLE IF NOT EXISTS job_attempts (
+    id              INTEGER PRIMARY KEY AUTOINCREMENT,
+    job_id          INTEGER NOT NULL,
+    started_at      INTEGER NOT NULL,
+    finished_at     INTEGER,
+    outcome         TEXT,
+    error           TEXT,
+    category        TEXT,
+    error_chain     TEXT,
+    upstream_status INTEGER
+);
+CREATE INDEX IF NOT EXISTS job_attempts_job ON job_attempts (job_id);
+CREATE TABLE IF NOT EXISTS idempotency_keys (
+    client     TEXT NOT NULL,
+    key        TEXT NOT NULL,
+    job_id     INTEGER NOT NULL,
+    track_id   TEXT NOT NULL,
+    hash       TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (client, key)
+);
+CREATE INDEX IF NOT EXISTS idempotency_keys_created ON idempotency_keys (created_at);
+CREATE TABLE IF NOT EXISTS collection_jobs (
+    job_id INTEGER PRIMARY KEY,
+    hash   TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS batch_digests (
+    label       TEXT PRIMARY KEY,
+    last_job_id INTEGER NOT NULL,
+    sent_at     INTEGER NOT NULL
+);
+";
+
+const JOB_COLUMNS: &str =
+    "id, track_id, hash, state, error, created_at, updated_at, started_at, finished_at, label, tenant, request_id, \
+     priority, not_before";
+
+fn job_from_row(row: &Row) -> rusqlite::Result<Job> {
+    let state: String = row.get(3)?;
+    let priority: Option<String> = row.get(12)?;
+    Ok(Job {
+        id: row.get(0)?,
+        track_id: row.get(1)?,
+        hash: row.get(2)?,
+        state: JobState::parse(&state).unwrap_or(JobState::Failed),
+        error: row.get(4)?,
+        created_at: row.get(5)?,
+        updated_at: row.get(6)?,
+        started_at: row.get(7)?,
+        finished_at: row.get(8)?,
+        label: row.get(9)?,
+        tenant: row.get(10)?,
+        request_id: row.get(11)?,
+        priority: priority.as_deref().and_then(Priority::parse).unwrap_or_default(),
+        not_before: row.get(13)?,
+    })
+}
+
+fn insert_job(conn: &Connection, params: &DownloadZVUK, owner: &Owner) -> rusqlite::Result<i64> {
+    let now = unix_now() as i64;
+    conn.execute(
+        "INSERT INTO jobs (track_id, hash, params, state, created_at, updated_at, client, tenant, label, request_id,
+                           priority, not_before)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        params![
+            params.id,
+            params.hash,
+            serde_json::to_string(params).expect("payload serializes"),
+            JobState::Queued.as_str(),
+            now,
+            owner.client,
+            owner.tenant,
+            params.label,
+            owner.request_id,
+            params.priority.unwrap_or_default().as_str(),
+            params.not_before
+        ],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+fn find_claim(
+    conn: &Connection,
+    params: &DownloadZVUK,
+    owner: &Owner,
+    key: &str,
+    since: i64,
+) -> rusqlite::Result<Option<Claim>> {
+    let existing: Option<(i64, String, String)> = conn
+        .query_row(
+            "SELECT k.job_id, k.track_id, k.hash FROM idempotency_keys k JOIN jobs j ON j.id = k.job_id
+             WHERE k.client = ?1 AND k.key = ?2 AND k.created_at >= ?3 AND j.state != 'cancelled'",
+            params![owner.client.unwrap_or_default(), key, since],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?;
+    Ok(existing.map(|(id, track_id, hash)| {
+        if track_id == params.id && hash == params.hash {
+            Claim::Existing(id)
+        } else {
+            Claim::Conflict
+        }
+    }))
+}
+
+/// What an idempotency key refers to.
+pub enum Claim {
+    Inserted(i64),
+    /// The key already started this job.
+    Existing(i64),
+    /// The key was used for a different track or hash.
+    Conflict,
+}
+
+pub struct JobStore {
+    conn: Mutex<Connection>,
+}
+
+impl JobStore {
+    pub fn open(path: &Path) -> rusqlite::Result<JobStore> {
+        let conn = db::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        db::ensure_column(&conn, "jobs", "client", "TEXT")?;
+        db::ensure_column(&conn, "jobs", "bytes", "INTEGER")?;
+        db::ensure_column(&conn, "jobs", "label", "TEXT")?;
+        db::ensure_column(&conn, "jobs", "tenant", "TEXT")?;
+        db::ensure_column(&conn, "jobs", "request_id", "TEXT")?;
+        db::ensure_column(&conn, "jobs", "priority", "TEXT")?;
+        db::ensure_column(&conn, "jobs", "not_before", "INTEGER")?;
+        conn.execute("CREATE INDEX IF NOT EXISTS jobs_client ON jobs (client)", [])?;
+        conn.execute("CREATE INDEX IF NOT EXISTS jobs_label ON jobs (label)", [])?;
+        Ok(JobStore { conn: Mutex::new(conn) })
+    }
+
+    pub fn insert(&self, params: &DownloadZVUK, owner: &Owner) -> rusqlite::Result<i64> {
+        insert_job(&self.conn.lock().unwrap(), params, owner)
+    }
+
+    /// What `key` already started for the same client within the last
+    /// `window` seconds, unless that job was cancelled.
+    pub fn claimed(&self, params: &DownloadZVUK, owner: &Owner, key: &str, window: u64) -> rusqlite::Result<Option<Claim>> {
+        let since = unix_now() as i64 - window as i64;
+        find_claim(&self.conn.lock().unwrap(), params, owner, key, since)
+    }
+
+    /// Inserts a job and binds `key` to it, unless the key was
+    /// [claimed](Self::claimed) in the meantime.
+    pub fn insert_once(&self, params: &DownloadZVUK, owner: &Owner, key: &str, window: u64) -> rusqlite::Result<Claim> {
+        let now = unix_now() as i64;
+        let client = owner.client.unwrap_or_default();
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM idempotency_keys WHERE created_at < ?1", [now - window as i64])?;
+        if let Some(claim) = find_claim(&tx, params, owner, key, now - window as i64)? {
+            return Ok(claim);
+        }
+        let id = insert_job(&tx, params, owner)?;
+        tx.execute(
+            "INSERT INTO idempotency_keys (client, key, job_id, track_id, hash, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT (client, key) DO UPDATE SET job_id = ?3, track_id = ?4, hash = ?5, created_at = ?6",
+            params![client, key, id, params.id, params.hash, now],
+        )?;
+        tx.commit()?;
+        Ok(Claim::Inserted(id))
+    }
+
+    /// Jobs of `client` that are queued or running.
+    pub fn active_for(&self, client: &str) -> rusqlite::Result<u32> {
+        self.conn.lock().unwrap().query_row(
+            "SELECT COUNT(*) FROM jobs WHERE client = ?1 AND state IN ('queued', 'running')",
+            [client],
+            |row| row.get(0),
+        )
+    }
+
+    /// Bytes downloaded by `client` since `since`, and the finish time of the
+    /// oldest job counted.
+    pub fn bytes_for(&self, client: &str, since: i64) -> rusqlite::Result<(u64, Option<i64>)> {
+        self.conn.lock().unwrap().query_row(
+            "SELECT COALESCE(SUM(bytes), 0), MIN(finished_at) FROM jobs
+             WHERE client = ?1 AND finished_at >= ?2 AND bytes IS NOT NULL",
+            params![client, since],
+            |row| Ok((row.get::<_, i64>(0)? as u64, row.get(1)?)),
+        )
+    }
+
+    pub fn set_bytes(&self, id: i64, bytes: u64) -> rusqlite::Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("UPDATE jobs SET bytes = ?2 WHERE id = ?1", params![id, bytes as i64])?;
+        Ok(())
+    }
+
+    /// Records that the jobs `ids` download tracks of the collection `hash`.
+    pub fn link_collection(&self, hash: &str, ids: &[i64]) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for id in ids {
+            tx.execute(
+                "INSERT OR REPLACE INTO collection_jobs (job_id, hash) VALUES (?1, ?2)",
+                params![id, hash],
+            )?;
+        }
+        tx.commit()
+    }
+
+    /// The collection a job downloads a track of, if any.
+    pub fn collection_of(&self, id: i64) -> rusqlite::Result<Option<String>> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT hash FROM collection_jobs WHERE job_id = ?1", [id], |row| row.get(0))
+            .optional()
+    }
+
+    pub fn ping(&self) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().query_row("SELECT 1", [], |_| Ok(()))
+    }
+
+    pub fn get(&self, id: i64) -> rusqlite::Result<Option<Job>> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                &format!("SELECT {} FROM jobs WHERE id = ?1", JOB_COLUMNS),
+                [id],
+                job_from_row,
+            )
+            .optional()
+    }
+
+    /// Priority and `not_before` of a job.
+    fn schedule(&self, id: i64) -> rusqlite::Result<(Priority, Option<i64>)> {
+        self.conn.lock().unwrap().query_row(
+            "SELECT priority, not_before FROM jobs WHERE id = ?1",
+            [id],
+            |row| {
+                let priority: Option<String> = row.get(0)?;
+                Ok((priority.as_deref().and_then(Priority::parse).unwrap_or_default(), row.get(1)?))
+            },
+        )
+    }
+
+    fn params(&self, id: i64) -> rusqlite::Result<Option<DownloadZVUK>> {
+        let raw: Option<String> = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT params FROM jobs WHERE id = ?1", [id], |row| row.get(0))
+            .optional()?;
+        Ok(raw.and_then(|raw| serde_json::from_str(&raw).ok()))
+    }
+
+    pub fn set_state(&self, id: i64, state: JobState, error: Option<&str>) -> rusqlite::Result<()> {
+        let now = unix_now() as i64;
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE jobs SET state = ?2, error = ?3, updated_at = ?4,
+                started_at = CASE WHEN ?2 = 'running' THEN ?4 ELSE started_at END,
+                finished_at = CASE WHEN ?2 IN ('done', 'failed', 'cancelled') THEN ?4 ELSE finished_at END
+             WHERE id = ?1",
+            params![id, state.as_str(), error, now],
+        )?;
+        Ok(())
+    }
+
+    /// Moves jobs interrupted by a restart back to `queued` and returns every
+    /// queued job in submission order.
+    pub fn recover(&self) -> rusqlite::Result<Vec<i64>> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE jobs SET state = 'queued', started_at = NULL WHERE state = 'running'",
+            [],
+        )?;
+        conn.execute(
+            "UPDATE job_attempts SET outcome = 'interrupted', finished_at = ?1 WHERE finished_at IS NULL",
+            [unix_now() as i64],
+        )?;
+        let mut stmt = conn.prepare("SELECT id FROM jobs WHERE state = 'queued' ORDER BY id")?;
+        stmt.query_map([], |row| row.get(0))?.collect()
+    }
+
+    pub fn start_attempt(&self, job_id: i64) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO job_attempts (job_id, started_at) VALUES (?1, ?2)",
+            params![job_id, unix_now() as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Closes the job's open attempt.
+    pub fn finish_attempt(&self, job_id: i64, outcome: &str, failure: Option<&Failure>) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE job_attempts SET finished_at = ?2, outcome = ?3, error = ?4, category = ?5,
+                error_chain = ?6, upstream_status = ?7
+             WHERE job_id = ?1 AND finished_at IS NULL",
+            params![
+                job_id,
+                unix_now() as i64,
+                outcome,
+                failure.map(|f| &f.message),
+                failure.map(|f| f.category.as_str()),
+                failure.map(|f| serde_json::to_string(&f.chain).expect("chain serializes")),
+                failure.and_then(|f| f.upstream_status)
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn attempts(&self, job_id: i64) -> rusqlite::Result<Vec<Attempt>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT started_at, finished_at, outcome, error, category, error_chain, upstream_status
+             FROM job_attempts WHERE job_id = ?1 ORDER BY id",
+        )?;
+        let rows = stmt.query_map([job_id], |row| {
+            let category: Option<String> = row.get(4)?;
+            let chain: Option<String> = row.get(5)?;
+            Ok(Attempt {
+                attempt: 0,
+                started_at: row.get(0)?,
+                finished_at: row.get(1)?,
+                outcome: row.get(2)?,
+                error: row.get(3)?,
+                category: category.as_deref().and_then(Category::parse),
+                error_chain: chain.and_then(|c| serde_json::from_str(&c).ok()).unwrap_or_default(),
+                upstream_status: row.get(6)?,
+            })
+        })?;
+        let mut attempts = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+        for (i, attempt) in attempts.iter_mut().enumerate() {
+            attempt.attempt = i as u32 + 1;
+        }
+        Ok(attempts)
+    }
+
+    /// Finished jobs per day and the most common failure categories since
+    /// `since`.
+    pub fn totals(&self, since: i64, top: usize) -> rusqlite::Result<Totals> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT date(finished_at, 'unixepoch') AS day, SUM(state = 'done'), SUM(state = 'failed'),
+                    COALESCE(SUM(CASE WHEN state = 'done' THEN bytes END), 0)
+             FROM jobs WHERE finished_at >= ?1 AND state IN ('done', 'failed')
+             GROUP BY day ORDER BY day",
+        )?;
+        let by_day = stmt
+            .query_map([since], |row| {
+                Ok(DayStats {
+                    day: row.get(0)?,
+                    done: row.get::<_, i64>(1)? as u64,
+                    failed: row.get::<_, i64>(2)? as u64,
+                    bytes: row.get::<_, i64>(3)? as u64,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut stmt = conn.prepare(
+            "SELECT category, COUNT(*) FROM job_attempts
+             WHERE finished_at >= ?1 AND category IS NOT NULL
+             GROUP BY category ORDER BY COUNT(*) DESC, category LIMIT ?2",
+        )?;
+        let top_failures = stmt
+            .query_map(params![since, top as i64], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as u64))
+            })?
+            .filter_map(|row| match row {
+                Ok((category, count)) => Category::parse(&category).map(|category| Ok(FailureCount { category, count })),
+                Err(e) => Some(Err(e)),
+            })
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(Totals { by_day, top_failures })
+    }
+
+    /// Summarizes the jobs labelled `label` since its last digest, once none of
+    /// them is queued or running any more and there are at least `min_jobs`.
+    /// The batch is marked as digested, so each one is reported only once.
+    pub fn take_finished_batch(&self, label: &str, min_jobs: u32) -> rusqlite::Result<Option<BatchSummary>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let after: i64 = tx
+            .query_row("SELECT last_job_id FROM batch_digests WHERE label = ?1", [label], |row| row.get(0))
+            .optional()?
+            .unwrap_or(0);
+        let (total, pending, done, parked, cancelled, bytes, last_id): (u32, u32, u32, u32, u32, i64, i64) =
+            tx.query_row(
+                "SELECT COUNT(*),
+                        COALESCE(SUM(state IN ('queued', 'running')), 0),
+                        COALESCE(SUM(state = 'done'), 0),
+                        COALESCE(SUM(state = 'awaiting_credentials'), 0),
+                        COALESCE(SUM(state = 'cancelled'), 0),
+                        COALESCE(SUM(bytes), 0),
+                        COALESCE(MAX(id), 0)
+                 FROM jobs WHERE label = ?1 AND id > ?2",
+                params![label, after],
+                |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?))
+                },
+            )?;
+        if pending > 0 || total == 0 || total < min_jobs {
+            return Ok(None);
+        }
+        let failures = tx
+            .prepare(&format!(
+                "SELECT {} FROM jobs WHERE label = ?1 AND id > ?2 AND state IN ('failed', 'awaiting_credentials')
+                 ORDER BY id",
+                JOB_COLUMNS
+            ))?
+            .query_map(params![label, after], job_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        tx.execute(
+            "INSERT INTO batch_digests (label, last_job_id, sent_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (label) DO UPDATE SET last_job_id = ?2, sent_at = ?3",
+            params![label, last_id, unix_now() as i64],
+        )?;
+        tx.commit()?;
+        Ok(Some(BatchSummary {
+            label: label.to_string(),
+            total,
+            done,
+            awaiting_credentials: parked,
+            cancelled,
+            bytes: bytes as u64,
+            failures,
+        }))
+    }
+
+    /// Requeues the jobs of `client` parked on expired credentials, giving
+    /// them `auth_cookie`, and returns their IDs.
+    pub fn resume_parked(&self, client: &str, auth_cookie: &str) -> rusqlite::Result<Vec<i64>> {
+        let now = unix_now() as i64;
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let parked: Vec<(i64, String)> = tx
+            .prepare("SELECT id, params FROM jobs WHERE state = 'awaiting_credentials' AND client = ?1 ORDER BY id")?
+            .query_map([client], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        let mut resumed = Vec::new();
+        for (id, raw) in parked {
+            let Ok(mut params) = serde_json::from_str::<DownloadZVUK>(&raw) else {
+                continue;
+            };
+            params.auth_cookie = auth_cookie.to_string();
+            tx.execute(
+                "UPDATE jobs SET params = ?2, state = 'queued', error = NULL, updated_at = ?3, started_at = NULL
+                 WHERE id = ?1",
+                params![id, serde_json::to_string(&params).expect("payload serializes"), now],
+            )?;
+            resumed.push(id);
+        }
+        tx.commit()?;
+        Ok(resumed)
+    }
+
+    pub fn list(&self, filter: &JobFilter) -> rusqlite::Result<Vec<Job>> {
+        let mut sql = format!("SELECT {} FROM jobs WHERE 1 = 1", JOB_COLUMNS);
+        let mut args: Vec<rusqlite::types::Value> = Vec::new();
+        if let Some(state) = filter.state {
+            sql.push_str(" AND state = ?");
+            args.push(state.as_str().to_string().into());
+        }
+        if let Some(label) = &filter.label {
+            sql.push_str(" AND label = ?");
+            args.push(label.clone().into());
+        }
+        if let Some(tenant) = &filter.tenant {
+            sql.push_str(" AND tenant = ?");
+            args.push(tenant.clone().into());
+        }
+        if let Some(track_id) = &filter.track_id {
+            sql.push_str(" AND track_id = ?");
+            args.push(track_id.clone().into());
+        }
+        if let Some(hash) = &filter.hash {
+            sql.push_str(" AND hash = ?");
+            args.push(hash.clone().into());
+        }
+        if let Some(priority) = filter.priority {
+            sql.push_str(" AND priority = ?");
+            args.push(priority.as_str().to_string().into());
+        }
+        if let Some(since) = filter.since {
+            sql.push_str(" AND created_at >= ?");
+            args.push(since.into());
+        }
+        if let Some(until) = filter.until {
+            sql.push_str(" AND created_at < ?");
+            args.push(until.into());
+        }
+        sql.push_str(" ORDER BY id DESC LIMIT ? OFFSET ?");
+        args.push(i64::from(filter.limit.unwrap_or(50).min(500)).into());
+        args.push(i64::from(filter.offset.unwrap_or(0)).into());
+
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&sql)?;
+        stmt.query_map(params_from_iter(args), job_from_row)?.collect()
+    }
+}
+
+/// Jobs that finished on one UTC day.
+#[derive(Serialize)]
+pub struct DayStats {
+    /// `YYYY-MM-DD`.
+    pub day: String,
+    pub done: u64,
+    pub failed: u64,
+    /// Bytes written by the jobs that succeeded.
+    pub bytes: u64,
+}
+
+#[derive(Serialize)]
+pub struct FailureCount {
+    pub category: Category,
+    /// Failed attempts, retried ones included.
+    pub count: u64,
+}
+
+/// Totals over the jobs finished since some time.
+pub struct Totals {
+    pub by_day: Vec<DayStats>,
+    pub top_failures: Vec<FailureCount>,
+}
+
+/// Outcome of a finished batch (all jobs sharing a label).
+pub struct BatchSummary {
+    pub label: String,
+    pub total: u32,
+    pub done: u32,
+    pub awaiting_credentials: u32,
+    pub cancelled: u32,
+    pub bytes: u64,
+    /// Failed jobs and jobs waiting for credentials.
+    pub failures: Vec<Job>,
+}
+
+/// Who submitted a job: the rate-limited client, its tenant if any, and the
+/// request it came in on.
+#[derive(Default, Clone, Copy)]
+pub struct Owner<'a> {
+    pub client: Option<&'a str>,
+    pub tenant: Option<&'a str>,
+    pub request_id: Option<&'a str>,
+}
+
+/// A job accepted by [`JobQueue::submit`].
+#[derive(Clone, Copy, Debug)]
+pub enum Submitted {
+    New(i64),
+    /// Started earlier by a request with the same idempotency key.
+    Replayed(i64),
+}
+
+impl Submitted {
+    pub fn id(self) -> i64 {
+        match self {
+            Submitted::New(id) | Submitted::Replayed(id) => id,
+        }
+    }
+}
+
+/// Bytes written by a finished job, or why it failed.
+pub type Outcome = Result<u64, String>;
+
+/// One run of a job.
+#[derive(Serialize)]
+pub struct Attempt {
+    pub attempt: u32,
+    pub started_at: i64,
+    pub finished_at: Option<i64>,
+    /// `done`, `failed`, `awaiting_credentials`, `interrupted` or `cancelled`;
+    /// absent while running.
+    pub outcome: Option<String>,
+    pub error: Option<String>,
+    pub category: Option<Category>,
+    pub error_chain: Vec<String>,
+    pub upstream_status: Option<u16>,
+}
+
+#[derive(Debug)]
+pub enum SubmitError {
+    ShuttingDown,
+    TooManyJobs,
+    /// Byte quota used up; retry once enough of the window has passed.
+    QuotaExceeded { retry_after: Duration },
+    /// The idempotency key already started a job for another track or hash.
+    KeyReused,
+    /// The cache is down to its reserve of free space.
+    StorageFull(disk::StorageFull),
+    Store(rusqlite::Error),
+}
+
+impl fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SubmitError::ShuttingDown => write!(f, "service is shutting down"),
+            SubmitError::TooManyJobs => write!(f, "too many concurrent jobs for this client"),
+            SubmitError::QuotaExceeded { .. } => write!(f, "download quota exceeded for this client"),
+            SubmitError::KeyReused => write!(f, "Idempotency-Key was already used for a different download"),
+            SubmitError::StorageFull(e) => e.fmt(f),
+            SubmitError::Store(e) => write!(f, "couldn't record job: {}", e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for SubmitError {
+    fn from(e: rusqlite::Error) -> Self {
+        SubmitError::Store(e)
+    }
+}
+
+#[derive(Debug)]
+pub enum CancelError {
+    NotFound,
+    /// The job already ended in this state.
+    Finished(JobState),
+    Store(rusqlite::Error),
+}
+
+impl fmt::Display for CancelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CancelError::NotFound => write!(f, "no such job"),
+            CancelError::Finished(state) => write!(f, "job already {}", state.as_str()),
+            CancelError::Store(e) => write!(f, "couldn't cancel job: {}", e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for CancelError {
+    fn from(e: rusqlite::Error) -> Self {
+        CancelError::Store(e)
+    }
+}
+
+/// Cancels a job when dropped, unless [disarmed](Self::disarm) first. Held
+/// by requests waiting on their job, so the download stops (and its partial
+/// files are removed) if the client disconnects. When the request is dropped
+/// because its route timed out, the job is left to finish; resubmitting with
+/// the same idempotency key picks it up.
+pub struct CancelGuard {
+    id: Option<i64>,
+    deadline: Option<Deadline>,
+}
+
+impl CancelGuard {
+    pub fn new(id: i64, deadline: Option<Deadline>) -> Self {
+        CancelGuard { id: Some(id), deadline }
+    }
+
+    pub fn disarm(mut self) {
+        self.id = None;
+    }
+
+    /// The job to cancel on drop, if any.
+    fn abandoned(&mut self) -> Option<i64> {
+        let id = self.id.take()?;
+        if self.deadline.as_ref().is_some_and(Deadline::passed) {
+            tracing::info!(id, "request timed out before its job finished; leaving it running");
+            return None;
+        }
+        Some(id)
+    }
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        let Some(id) = self.abandoned() else {
+            return;
+        };
+        match queue().cancel(id) {
+            Ok(_) => tracing::info!(id, "request dropped before its job finished; cancelled it"),
+            Err(CancelError::Finished(_)) => {}
+            Err(e) => tracing::warn!(id, "couldn't cancel abandoned job: {}", e),
+        }
+    }
+}
+
+/// Jobs waiting for a worker.
+#[derive(Default)]
+struct Pending {
+    /// Jobs that may start now, indexed by [`Priority::rank`].
+    ready: [VecDeque<i64>; 3],
+    /// Jobs held back until a unix timestamp, with their priority.
+    scheduled: Vec<(i64, i64, Priority)>,
+}
+
+impl Pending {
+    /// Highest-priority job that may start at `now`, or else when the next
+    /// scheduled one becomes due.
+    fn pop(&mut self, now: i64) -> Result<i64, Option<i64>> {
+        self.scheduled.sort_unstable_by_key(|&(at, id, _)| (at, id));
+        let due = self.scheduled.partition_point(|&(at, ..)| at <= now);
+        for (_, id, priority) in self.scheduled.drain(..due) {
+            self.ready[priority.rank()].push_back(id);
+        }
+        match self.ready.iter_mut().find_map(|queue| queue.pop_front()) {
+            Some(id) => Ok(id),
+            None => Err(self.scheduled.first().map(|&(at, ..)| at)),
+        }
+    }
+
+    fn remove(&mut self, id: i64) -> bool {
+        let before = self.len();
+        for queue in &mut self.ready {
+            queue.retain(|&p| p != id);
+        }
+        self.scheduled.retain(|&(_, p, _)| p != id);
+        self.len() < before
+    }
+
+    fn len(&self) -> usize {
+        self.ready.iter().map(VecDeque::len).sum::<usize>() + self.scheduled.len()
+    }
+}
+
+/// Jobs of one priority waiting for a worker.
+#[derive(Serialize)]
+pub struct Depth {
+    pub priority: Priority,
+    /// May start as soon as a worker is free.
+    pub ready: usize,
+    /// Held back by `not_before` or a retry delay.
+    pub scheduled: usize,
+}
+
+/// A job that's downloading.
+struct Running {
+    hash: String,
+    /// What its entry held before it started, so a failure only removes
+    /// what it added.
+    before: EntrySnapshot,
+    abort: AbortHandle,
+}
+
+pub struct JobQueue {
+    pub store: JobStore,
+    pending: Mutex<Pending>,
+    notify: Notify,
+    waiters: Mutex<HashMap<i64, Vec<oneshot::Sender<Outcome>>>>,
+    accepting: AtomicBool,
+    /// Jobs currently downloading.
+    running: Mutex<HashMap<i64, Running>>,
+    /// Jobs to abort once (or as soon as) they run.
+    cancelling: Mutex<HashSet<i64>>,
+    idle: Notify,
+    /// Every state change, as the job looks afterwards.
+    events: broadcast::Sender<Job>,
+    /// No job is started while the cache is down to its reserve.
+    paused: AtomicBool,
+}
+
+/// How often a paused queue looks at free space again.
+pub const DISK_RECHECK: Duration = Duration::from_secs(30);
+
+static QUEUE: OnceCell<Arc<JobQueue>> = OnceCell::new();
+
+pub fn queue() -> &'static Arc<JobQueue> {
+    QUEUE.get().expect("job queue is not started")
+}
+
+/// Hashes of running jobs; none outside the server.
+pub fn running_hashes() -> HashSet<String> {
+    QUEUE.get().map(|queue| queue.running_hashes()).unwrap_or_default()
+}
+
+/// Opens the job database, re-enqueues interrupted jobs and spawns `workers`
+/// worker tasks.
+pub fn start(db: &Path, workers: usize) -> rusqlite::Result<()> {
+    let store = JobStore::open(db)?;
+    let recovered = store.recover()?;
+    let queue = Arc::new(JobQueue {
+        store,
+        pending: Mutex::new(Pending::default()),
+        notify: Notify::new(),
+        waiters: Mutex::new(HashMap::new()),
+        accepting: AtomicBool::new(true),
+        running: Mutex::new(HashMap::new()),
+        cancelling: Mutex::new(HashSet::new()),
+        idle: Notify::new(),
+        events: broadcast::channel(1024).0,
+        paused: AtomicBool::new(false),
+    });
+    QUEUE.set(queue.clone()).ok().expect("job queue started twice");
+    for id in recovered {
+        queue.enqueue(id);
+    }
+    for _ in 0..workers.max(1) {
+        tokio::spawn(queue.clone().work());
+    }
+    Ok(())
+}
+
+impl JobQueue {
+    fn admit(&self, owner: &Owner) -> Result<(), SubmitError> {
+        if !self.accepting.load(Ordering::SeqCst) {
+            return Err(SubmitError::ShuttingDown);
+        }
+        disk::check(0).map_err(SubmitError::StorageFull)?;
+        let Some(client) = owner.client else {
+            return Ok(());
+        };
+        let limits = &config::get().clients;
+        if let Some(max) = limits.max_concurrent_jobs
+            && self.store.active_for(client)? >= max
+        {
+            return Err(SubmitError::TooManyJobs);
+        }
+        if let Some(quota) = limits.byte_quota {
+            let window = limits.quota_window_secs as i64;
+            let now = unix_now() as i64;
+            let (used, oldest) = self.store.bytes_for(client, now - window)?;
+            if used >= quota {
+                let retry_after = oldest.map_or(window, |t| t + window - now).max(1);
+                return Err(SubmitError::QuotaExceeded {
+                    retry_after: Duration::from_secs(retry_after as u64),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Records a new job, or finds the one `key` already started.
+    fn insert(&self, params: &DownloadZVUK, owner: &Owner, key: Option<&str>) -> Result<Submitted, SubmitError> {
+        let Some(key) = key else {
+            self.admit(owner)?;
+            return Ok(Submitted::New(self.store.insert(params, owner)?));
+        };
+        let window = config::get().jobs.idempotency_window_secs;
+        let claim = match self.store.claimed(params, owner, key, window)? {
+            // A repeat isn't new work, so it isn't held to the client's limits.
+            Some(claim) => claim,
+            None => {
+                self.admit(owner)?;
+                self.store.insert_once(params, owner, key, window)?
+            }
+        };
+        match claim {
+            Claim::Inserted(id) => Ok(Submitted::New(id)),
+            Claim::Existing(id) => Ok(Submitted::Replayed(id)),
+            Claim::Conflict => Err(SubmitError::KeyReused),
+        }
+    }
+
+    /// Enqueues a download; with an idempotency `key`, a repeat of an earlier
+    /// request returns that request's job instead.
+    pub fn submit(&self, params: &DownloadZVUK, owner: &Owner, key: Option<&str>) -> Result<Submitted, SubmitError> {
+        let submitted = self.insert(params, owner, key)?;
+        if let Submitted::New(id) = submitted {
+            self.publish(id);
+            self.enqueue(id);
+        }
+        Ok(submitted)
+    }
+
+    /// Like [`submit`](Self::submit), but a new job also comes with a
+    /// receiver that resolves once it finishes.
+    pub fn submit_waiting(
+        &self,
+        params: &DownloadZVUK,
+        owner: &Owner,
+        key: Option<&str>,
+    ) -> Result<(Submitted, Option<oneshot::Receiver<Outcome>>), SubmitError> {
+        let submitted = self.insert(params, owner, key)?;
+        let Submitted::New(id) = submitted else {
+            return Ok((submitted, None));
+        };
+        self.publish(id);
+        let (tx, rx) = oneshot::channel();
+        self.waiters.lock().unwrap().entry(id).or_default().push(tx);
+        self.enqueue(id);
+        Ok((submitted, Some(rx)))
+    }
+
+    /// Subscribes to state changes of every job.
+    pub fn subscribe(&self) -> broadcast::Receiver<Job> {
+        self.events.subscribe()
+    }
+
+    /// The job once it has [settled](JobState::is_settled), or as it is after
+    /// `wait`; `None` if it doesn't exist.
+    pub async fn wait_settled(&self, id: i64, wait: Duration) -> rusqlite::Result<Option<Job>> {
+        // Subscribe before reading so a change in between isn't missed.
+        let mut events = self.subscribe();
+        let Some(job) = self.store.get(id)? else {
+            return Ok(None);
+        };
+        if job.state.is_settled() {
+            return Ok(Some(job));
+        }
+        let settled = timeout(wait, async {
+            loop {
+                match events.recv().await {
+                    Ok(job) if job.id == id && job.state.is_settled() => return Some(job),
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        if let Ok(Some(job)) = self.store.get(id)
+                            && job.state.is_settled()
+                        {
+                            return Some(job);
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+        .await;
+        match settled {
+            Ok(Some(job)) => Ok(Some(job)),
+            _ => self.store.get(id),
+        }
+    }
+
+    fn publish(&self, id: i64) {
+        if self.events.receiver_count() == 0 {
+            return;
+        }
+        if let Ok(Some(job)) = self.store.get(id) {
+            let _ = self.events.send(job);
+        }
+    }
+
+    fn set_state(&self, id: i64, state: JobState, error: Option<&str>) -> rusqlite::Result<()> {
+        self.store.set_state(id, state, error)?;
+        self.publish(id);
+        Ok(())
+    }
+
+    fn enqueue(&self, id: i64) {
+        self.enqueue_at(id, None);
+    }
+
+    /// Queues a job at its priority, to start no earlier than `at` or its
+    /// `not_before`.
+    fn enqueue_at(&self, id: i64, at: Option<i64>) {
+        let (priority, not_before) = self.store.schedule(id).unwrap_or_else(|e| {
+            tracing::warn!(id, "couldn't read job schedule: {}", e);
+            (Priority::default(), None)
+        });
+        let mut pending = self.pending.lock().unwrap();
+        match at.max(not_before) {
+            Some(at) if at > unix_now() as i64 => pending.scheduled.push((at, id, priority)),
+            _ => pending.ready[priority.rank()].push_back(id),
+        }
+        drop(pending);
+        // Every idle worker rechecks, so they also recompute when the next
+        // scheduled job is due.
+        self.notify.notify_waiters();
+    }
+
+    /// Waiting jobs per priority.
+    pub fn depth(&self) -> Vec<Depth> {
+        let pending = self.pending.lock().unwrap();
+        Priority::ALL
+            .into_iter()
+            .map(|priority| Depth {
+                priority,
+                ready: pending.ready[priority.rank()].len(),
+                scheduled: pending.scheduled.iter().filter(|&&(.., p)| p == priority).count(),
+            })
+            .collect()
+    }
+
+    /// When the earliest scheduled job becomes due.
+    pub fn next_scheduled(&self) -> Option<i64> {
+        self.pending.lock().unwrap().scheduled.iter().map(|&(at, ..)| at).min()
+    }
+
+    /// Jobs downloading right now.
+    pub fn running_count(&self) -> usize {
+        self.running.lock().unwrap().len()
+    }
+
+    /// Whether workers are holding off for lack of free space.
+    pub fn paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Next job to run, or `None` once the queue is shutting down.
+    async fn next(&self) -> Option<i64> {
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if !self.accepting.load(Ordering::SeqCst) {
+                return None;
+            }
+            if let Err(full) = disk::check(0) {
+                if !self.paused.swap(true, Ordering::SeqCst) {
+                    tracing::warn!("pausing the queue: {}", full);
+                }
+                let _ = timeout(DISK_RECHECK, notified).await;
+                continue;
+            }
+            if self.paused.swap(false, Ordering::SeqCst) {
+                tracing::info!("free space is back above the reserve; resuming the queue");
+            }
+            let now = unix_now() as i64;
+            let wake = match self.pending.lock().unwrap().pop(now) {
+                Ok(id) => return Some(id),
+                Err(wake) => wake,
+            };
+            match wake {
+                Some(at) => {
+                    let _ = timeout(Duration::from_secs((at - now) as u64), notified).await;
+                }
+                None => notified.await,
+            }
+        }
+    }
+
+    /// Span covering everything a job does, tied to the submitting request.
+    fn span(&self, id: i64) -> tracing::Span {
+        let job = self.store.get(id).ok().flatten();
+        let request_id = job.as_ref().and_then(|j| j.request_id.as_deref()).unwrap_or("-");
+        let track_id = job.as_ref().map(|j| j.track_id.as_str()).unwrap_or("-");
+        tracing::info_span!("job", id, track_id, request_id)
+    }
+
+    async fn work(self: Arc<Self>) {
+        while let Some(id) = self.next().await {
+            async {
+                match self.run(id).await {
+                    Some(Ok(bytes)) => self.finish(id, Ok(bytes)),
+                    Some(Err(failure)) => {
+                        self.clean_up(id).await;
+                        match self.retry(id, failure) {
+                            Ok(()) => {}
+                            Err(failure) => self.finish(id, Err(failure)),
+                        }
+                    }
+                    None if self.cancelling.lock().unwrap().remove(&id) => self.cancelled(id).await,
+                    None => self.interrupted(id).await,
+                }
+            }
+            .instrument(self.span(id))
+            .await;
+            self.running.lock().unwrap().remove(&id);
+            self.cancelling.lock().unwrap().remove(&id);
+            self.idle.notify_waiters();
+        }
+    }
+
+    /// Requeues a job after `[jobs] retry_delay_secs` if its failure is
+    /// retriable and it has attempts left; otherwise hands the failure back.
+    fn retry(&self, id: i64, failure: Failure) -> Result<(), Failure> {
+        let jobs = &config::get().jobs;
+        let attempts = self.store.attempts(id).map(|a| a.len()).unwrap_or(usize::MAX);
+        if !failure.category.retriable() || attempts > jobs.retries as usize {
+            return Err(failure);
+        }
+        if let Err(e) = self.store.finish_attempt(id, "retrying", Some(&failure)) {
+            tracing::warn!("couldn't record attempt: {}", e);
+        }
+        tracing::warn!(attempts, "job failed, retrying in {}s: {}", jobs.retry_delay_secs, failure.message);
+        if let Err(e) = self.set_state(id, JobState::Queued, Some(&failure.message)) {
+            tracing::warn!("couldn't record state: {}", e);
+        }
+        self.enqueue_at(id, Some(unix_now() as i64 + jobs.retry_delay_secs as i64));
+        Ok(())
+    }
+
+    fn finish(&self, id: i64, result: Result<u64, Failure>) {
+        let (state, failure) = match &result {
+            Ok(_) => (JobState::Done, None),
+            Err(f) if f.category == Category::AuthExpired => (JobState::AwaitingCredentials, Some(f)),
+            Err(f) => (JobState::Failed, Some(f)),
+        };
+        if let Err(e) = self.store.finish_attempt(id, state.as_str(), failure) {
+            tracing::warn!("couldn't record attempt: {}", e);
+        }
+        let outcome = result.map_err(|f| f.message);
+        let error = outcome.as_ref().err().map(String::as_str);
+        match error {
+            None => tracing::info!(state = state.as_str(), "job finished"),
+            Some(e) => tracing::warn!(state = state.as_str(), "job failed: {}", e),
+        }
+        if let Ok(bytes) = outcome
+            && let Err(e) = self.store.set_bytes(id, bytes)
+        {
+            tracing::warn!("couldn't record size: {}", e);
+        }
+        if let Err(e) = self.set_state(id, state, error) {
+            tracing::warn!("couldn't record state: {}", e);
+        }
+        let outcome = match (state, outcome) {
+            (JobState::AwaitingCredentials, Err(e)) => {
+                Err(format!("{}; job {} will resume once the session is validated", e, id))
+            }
+            (_, outcome) => outcome,
+        };
+        for tx in self.waiters.lock().unwrap().remove(&id).unwrap_or_default() {
+            let _ = tx.send(outcome.clone());
+        }
+        self.settled(id);
+    }
+
+    /// Follow-ups once a job won't change without someone acting on it.
+    fn settled(&self, id: i64) {
+        self.digest(id);
+        match self.store.collection_of(id) {
+            Ok(Some(hash)) => {
+                tokio::spawn(async move {
+                    if let Err(e) = collection::refresh(&hash).await {
+                        tracing::warn!("collection {}: couldn't refresh: {}", hash, e);
+                    }
+                });
+            }
+            Ok(None) => {}
+            Err(e) => tracing::warn!("couldn't look up collection: {}", e),
+        }
+    }
+
+    /// Emails a digest if `id` was the last outstanding job of its batch.
+    fn digest(&self, id: i64) {
+        let Some(email) = &config::get().email else {
+            return;
+        };
+        let Ok(Some(Job { label: Some(label), .. })) = self.store.get(id) else {
+            return;
+        };
+        match self.store.take_finished_batch(&label, email.min_jobs) {
+            Ok(Some(summary)) => {
+                tokio::spawn(async move {
+                    if let Err(e) = digest::send(&summary).await {
+                        tracing::warn!("batch {}: couldn't send digest: {}", summary.label, e);
+                    }
+                });
+            }
+            Ok(None) => {}
+            Err(e) => tracing::warn!("batch {}: couldn't summarize: {}", label, e),
+        }
+    }
+
+    /// Requeues jobs parked on expired credentials for `client` with a
+    /// freshly validated cookie.
+    pub fn resume_parked(&self, client: &str, auth_cookie: &str) -> Result<Vec<i64>, SubmitError> {
+        if !self.accepting.load(Ordering::SeqCst) {
+            return Err(SubmitError::ShuttingDown);
+        }
+        let resumed = self.store.resume_parked(client, auth_cookie)?;
+        for &id in &resumed {
+            self.publish(id);
+            self.enqueue(id);
+        }
+        Ok(resumed)
+    }
+
+    /// Hashes of the jobs currently downloading.
+    pub fn running_hashes(&self) -> HashSet<String> {
+        self.running.lock().unwrap().values().map(|r| r.hash.clone()).collect()
+    }
+
+    /// Removes whatever a failed or aborted job half-wrote.
+    async fn clean_up(&self, id: i64) {
+        let running = self.running.lock().unwrap().get(&id).map(|r| (r.hash.clone(), r.before.clone()));
+        if let Some((hash, before)) = running
+            && let Err(e) = cleanup_incomplete(&entry_dir(&hash), &before).await
+        {
+            tracing::warn!("couldn't clean up partial files: {}", e);
+        }
+    }
+
+    /// A job aborted by shutdown goes back to `queued` so it's picked up again
+    /// after restart; whatever it half-wrote is removed.
+    async fn interrupted(&self, id: i64) {
+        self.clean_up(id).await;
+        if let Err(e) = self.store.finish_attempt(id, "interrupted", None) {
+            tracing::warn!("couldn't record attempt: {}", e);
+        }
+        if let Err(e) = self.set_state(id, JobState::Queued, None) {
+            tracing::warn!("couldn't record state: {}", e);
+        }
+        for tx in self.waiters.lock().unwrap().remove(&id).unwrap_or_default() {
+            let _ = tx.send(Err("interrupted by shutdown".to_string()));
+        }
+    }
+
+    /// Cancels a queued or parked job right away; a running one is aborted
+    /// and cleaned up by its worker. Returns the state it was in.
+    pub fn cancel(&self, id: i64) -> Result<JobState, CancelError> {
+        let job = self.store.get(id)?.ok_or(CancelError::NotFound)?;
+        match job.state {
+            JobState::Done | JobState::Failed | JobState::Cancelled => Err(CancelError::Finished(job.state)),
+            JobState::AwaitingCredentials => {
+                self.set_state(id, JobState::Cancelled, None)?;
+                self.settled(id);
+                Ok(job.state)
+            }
+            JobState::Queued | JobState::Running => {
+                let dequeued = self.pending.lock().unwrap().remove(id);
+                if dequeued {
+                    self.set_state(id, JobState::Cancelled, None)?;
+                    self.notify_cancelled(id);
+                    return Ok(job.state);
+                }
+                // Picked up by a worker; `run` checks this set once the job
+                // is registered, so it's aborted even if it isn't yet.
+                self.cancelling.lock().unwrap().insert(id);
+                if let Some(running) = self.running.lock().unwrap().get(&id) {
+                    running.abort.abort();
+                }
+                Ok(job.state)
+            }
+        }
+    }
+
+    fn notify_cancelled(&self, id: i64) {
+        for tx in self.waiters.lock().unwrap().remove(&id).unwrap_or_default() {
+            let _ = tx.send(Err("cancelled".to_string()));
+        }
+        self.settled(id);
+    }
+
+    /// A running job aborted by [`cancel`](Self::cancel); like
+    /// [`interrupted`](Self::interrupted), but it isn't requeued.
+    async fn cancelled(&self, id: i64) {
+        self.clean_up(id).await;
+        if let Err(e) = self.store.finish_attempt(id, JobState::Cancelled.as_str(), None) {
+            tracing::warn!("couldn't record attempt: {}", e);
+        }
+        if let Err(e) = self.set_state(id, JobState::Cancelled, None) {
+            tracing::warn!("couldn't record state: {}", e);
+        }
+        tracing::info!("job cancelled");
+        self.notify_cancelled(id);
+    }
+
+    /// Runs a job to completion; `None` means it was aborted by shutdown or
+    /// cancelled.
+    async fn run(&self, id: i64) -> Option<Result<u64, Failure>> {
+        if self.cancelling.lock().unwrap().contains(&id) {
+            return None;
+        }
+        let params = match self.store.params(id) {
+            Ok(Some(params)) => params,
+            Ok(None) => return Some(Err("job parameters are missing or unreadable".to_string().into())),
+            Err(e) => return Some(Err(e.to_string().into())),
+        };
+        if let Err(e) = self.set_state(id, JobState::Running, None) {
+            return Some(Err(e.to_string().into()));
+        }
+        if let Err(e) = self.store.start_attempt(id) {
+            tracing::warn!("couldn't record attempt: {}", e);
+        }
+
+        let hash = params.hash.clone();
+        // Until the job is listed as running, nothing else may add files.
+        let claim = manifest::lock_entries().await;
+        let before = match EntrySnapshot::take(&entry_dir(&hash)).await {
+            Ok(before) => before,
+            Err(e) => return Some(Err(Failure::classify(&e))),
+        };
+        let task = tokio::spawn(
+            async move {
+                let save = throttle::with_limit(params.max_kbps, save_by_id(&params));
+                zvuk::with_proxy(params.proxy.clone(), save)
+                    .await
+                    .map_err(|e| Failure::classify(&*e))
+            }
+            .in_current_span(),
+        );
+        self.running
+            .lock()
+            .unwrap()
+            .insert(id, Running { hash, before, abort: task.abort_handle() });
+        drop(claim);
+        if self.cancelling.lock().unwrap().contains(&id) {
+            task.abort();
+        }
+
+        match task.await {
+            Ok(result) => Some(result),
+            Err(e) if e.is_cancelled() => None,
+            Err(e) if e.is_panic() => {
+                let panic = e.into_panic();
+                let msg = panic
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                Some(Err(Failure::new(Category::Panic, format!("panic: {}", msg))))
+            }
+            Err(e) => Some(Err(e.to_string().into())),
+        }
+    }
+
+    async fn drained(&self) {
+        loop {
+            let idle = self.idle.notified();
+            tokio::pin!(idle);
+            idle.as_mut().enable();
+            if self.running.lock().unwrap().is_empty() {
+                return;
+            }
+            idle.await;
+        }
+    }
+
+    /// Stops accepting jobs and waits up to `grace` for running downloads to
+    /// finish; anything still running after that is aborted and requeued.
+    pub async fn shutdown(&self, grace: Duration) {
+        self.accepting.store(false, Ordering::SeqCst);
+        self.notify.notify_waiters();
+        if timeout(grace, self.drained()).await.is_ok() {
+            return;
+        }
+        for running in self.running.lock().unwrap().values() {
+            running.abort.abort();
+        }
+        let _ = timeout(Duration::from_secs(5), self.drained()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> (tempfile::TempDir, JobStore) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = JobStore::open(&dir.path().join("jobs.sqlite3")).unwrap();
+        (dir, store)
+    }
+
+    fn payload(id: &str, hash: &str) -> DownloadZVUK {
+        serde_json::from_value(serde_json::json!({ "id": id, "hash": hash })).unwrap()
+    }
+
+    const OWNER: Owner = Owner { client: Some("key:abc"), tenant: None, request_id: None };
+    const WINDOW: u64 = 3600;
+
+    fn inserted(claim: Claim) -> i64 {
+        match claim {
+            Claim::Inserted(id) => id,
+            _ => panic!("expected a new job"),
+        }
+    }
+
+    #[test]
+    fn same_key_and_body_returns_the_first_job() {
+        let (_dir, store) = store();
+        let id = inserted(store.insert_once(&payload("1", "h"), &OWNER, "k", WINDOW).unwrap());
+        let again = store.claimed(&payload("1", "h"), &OWNER, "k", WINDOW).unwrap();
+        assert!(matches!(again, Some(Claim::Existing(existing)) if existing == id));
+        let again = store.insert_once(&payload("1", "h"), &OWNER, "k", WINDOW).unwrap();
+        assert!(matches!(again, Claim::Existing(existing) if existing == id));
+    }
+
+    #[test]
+    fn same_key_with_another_body_conflicts() {
+        let (_dir, store) = store();
+        inserted(store.insert_once(&payload("1", "h"), &OWNER, "k", WINDOW).unwrap());
+        let other_track = store.claimed(&payload("2", "h"), &OWNER, "k", WINDOW).unwrap();
+        assert!(matches!(other_track, Some(Claim::Conflict)));
+        let other_hash = store.insert_once(&payload("1", "h2"), &OWNER, "k", WINDOW).unwrap();
+        assert!(matches!(other_hash, Claim::Conflict));
+    }
+
+    #[test]
+    fn keys_are_per_client() {
+        let (_dir, store) = store();
+        inserted(store.insert_once(&payload("1", "h"), &OWNER, "k", WINDOW).unwrap());
+        let other = Owner { client: Some("key:def"), ..OWNER };
+        assert!(store.claimed(&payload("1", "h"), &other, "k", WINDOW).unwrap().is_none());
+    }
+
+    #[test]
+    fn expired_key_is_free_again() {
+        let (_dir, store) = store();
+        let first = inserted(store.insert_once(&payload("1", "h"), &OWNER, "k", WINDOW).unwrap());
+        let conn = store.conn.lock().unwrap();
+        let later = unix_now() as i64 + 1;
+        assert!(find_claim(&conn, &payload("1", "h"), &OWNER, "k", later).unwrap().is_none());
+        drop(conn);
+        let age = "UPDATE idempotency_keys SET created_at = created_at - ?1";
+        store.conn.lock().unwrap().execute(age, [WINDOW + 1]).unwrap();
+        let second = inserted(store.insert_once(&payload("2", "h"), &OWNER, "k", WINDOW).unwrap());
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn key_of_a_cancelled_job_starts_a_new_one() {
+        let (_dir, store) = store();
+        let first = inserted(store.insert_once(&payload("1", "h"), &OWNER, "k", WINDOW).unwrap());
+        store.set_state(first, JobState::Cancelled, None).unwrap();
+        assert!(store.claimed(&payload("1", "h"), &OWNER, "k", WINDOW).unwrap().is_none());
+        let second = inserted(store.insert_once(&payload("1", "h"), &OWNER, "k", WINDOW).unwrap());
+        assert_ne!(first, second);
+        let again = store.claimed(&payload("1", "h"), &OWNER, "k", WINDOW).unwrap();
+        assert!(matches!(again, Some(Claim::Existing(id)) if id == second));
+    }
+
+    #[test]
+    fn timed_out_request_leaves_its_job_running() {
+        let deadline = Deadline::default();
+        let mut guard = CancelGuard::new(7, Some(deadline.clone()));
+        deadline.pass();
+        assert_eq!(guard.abandoned(), None);
+    }
+
+    #[test]
+    fn disconnected_request_cancels_its_job() {
+        let mut guard = CancelGuard::new(7, Some(Deadline::default()));
+        assert_eq!(guard.abandoned(), Some(7));
+        let mut guard = CancelGuard::new(8, None);
+        assert_eq!(guard.abandoned(), Some(8));
+    }
+
+    #[test]
+    fn disarmed_guard_cancels_nothing() {
+        let mut guard = CancelGuard::new(7, None);
+        guard.id = None;
+        assert_eq!(guard.abandoned(), None);
+    }
+
+    fn pending(jobs: &[(i64, Priority)]) -> Pending {
+        let mut pending = Pending::default();
+        for &(id, priority) in jobs {
+            pending.ready[priority.rank()].push_back(id);
+        }
+        pending
+    }
+
+    #[test]
+    fn higher_priorities_start_first() {
+        let mut pending = pending(&[(1, Priority::Low), (2, Priority::Normal), (3, Priority::High)]);
+        assert_eq!(pending.pop(0), Ok(3));
+        assert_eq!(pending.pop(0), Ok(2));
+        assert_eq!(pending.pop(0), Ok(1));
+    }
+
+    #[test]
+    fn same_priority_starts_oldest_first() {
+        let mut pending = pending(&[(1, Priority::Normal), (2, Priority::Normal), (3, Priority::Normal)]);
+        assert_eq!(pending.pop(0), Ok(1));
+        assert_eq!(pending.pop(0), Ok(2));
+        assert_eq!(pending.pop(0), Ok(3));
+    }
+
+    #[test]
+    fn scheduled_jobs_wait_until_due() {
+        let mut pending = Pending::default();
+        pending.scheduled.push((200, 1, Priority::High));
+        pending.scheduled.push((100, 2, Priority::Low));
+        assert_eq!(pending.pop(50), Err(Some(100)));
+        assert_eq!(pending.pop(100), Ok(2));
+        assert_eq!(pending.pop(150), Err(Some(200)));
+        // Once due, a scheduled job queues by priority like any other.
+        pending.ready[Priority::Normal.rank()].push_back(3);
+        assert_eq!(pending.pop(200), Ok(1));
+        assert_eq!(pending.pop(200), Ok(3));
+    }
+
+    #[test]
+    fn empty_queue_has_nothing_to_wait_for() {
+        let mut pending = Pending::default();
+        assert_eq!(pending.pop(0), Err(None));
+        assert_eq!(pending.len(), 0);
+    }
+}
@@ -0,0 +1,2168 @@
+use std::{ collections::{HashMap, HashSet}, convert::Infallible, env, error::Error, net::SocketAddr, path::PathBuf, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use axum::extract::{Path, Query};
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use axum::middleware::from_fn_with_state;
+use axum::serve::ListenerExt;
+use axum::routing::{delete, get, post};
+use axum::Extension;
+use axum::{response::IntoResponse, Router};
+use axum::extract::DefaultBodyLimit;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use hyper::StatusCode;
+use once_cell::sync::Lazy;
+use futures_util::future::{try_join_all, FutureExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use error::ApiError;
+use validate::{Valid, Validate};
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, RequestId, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    Stream, StreamExt,
+};
+
+mod accounts;
+mod aliases;
+mod auth;
+mod collection;
+pub mod cli;
+mod compare;
+mod config;
+mod db;
+mod dedupe;
+mod diagnose;
+mod digest;
+mod disk;
+mod error;
+mod failure;
+mod gc;
+mod grpc;
+mod health;
+mod jobs;
+mod limits;
+mod loudness;
+mod manifest;
+mod metadata;
+mod segmented;
+mod sniff;
+#[cfg(unix)]
+mod socket;
+mod stream;
+mod tenant;
+mod throttle;
+mod tls;
+mod transcode;
+mod validate;
+pub mod zvuk;
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// File extension for a CDN content type, empty if unknown.
+pub fn extension_for(content_type: Option<&str>) -> String {
+    content_type
+        .and_then(|ct| ct.parse::<mime::Mime>().ok())
+        .and_then(|mime| mime_guess::get_mime_extensions(&mime))
+        .and_then(|guess| guess.first().map(|ext| ext.to_string()))
+        .unwrap_or_default()
+}
+
+/// Streams `url` into `to` (plus an extension for the sniffed format, or
+/// guessed from the content type) and verifies the result: the byte count
+/// must match `Content-Length`, and the file re-read from disk must hash to
+/// what was received. With `[download] segments` above 1, large files are
+/// fetched as that many concurrent ranges instead, if the CDN allows it;
+/// those are checked by size only.
+#[tracing::instrument(name = "cdn_download", skip(url))]
+async fn dl_file(url: &str, to: &str) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+    let http = zvuk::http();
+    let segments = config::get().download.segments;
+    let mut req = http.get(url);
+    if segments > 1 {
+        req = req.header(reqwest::header::RANGE, "bytes=0-");
+    }
+    let mut resp = req.send().await?.error_for_status()?;
+    let ct = resp
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .map(str::to_owned);
+    let part_path = format!("{}.{}", to, PART_EXT);
+    // Also the full size when answering `bytes=0-`.
+    if let Some(len) = resp.content_length() {
+        disk::check(len)?;
+    }
+
+    let (received, mismatch) = if let Some(plan) = segmented::plan(&resp, segments) {
+        let received = match segmented::fetch(&http, url, resp, std::path::Path::new(&part_path), &plan).await {
+            Ok(received) => received,
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&part_path).await;
+                return Err(e);
+            }
+        };
+        let record = manifest::file_record(std::path::Path::new(&part_path)).await?;
+        let mismatch = (received != plan.total || record.size != plan.total)
+            .then(|| format!("truncated download ({} of {} bytes)", received.min(record.size), plan.total));
+        (received, mismatch)
+    } else {
+        let expected_len = resp.content_length();
+        let mut file = tokio::fs::File::create(&part_path).await?;
+        let mut hasher = Sha256::new();
+        let mut received = 0u64;
+        while let Some(chunk) = resp.chunk().await? {
+            hasher.update(&chunk);
+            received += chunk.len() as u64;
+            file.write_all(&chunk).await?;
+            throttle::consume(chunk.len()).await;
+        }
+        file.flush().await?;
+        drop(file);
+
+        let record = manifest::file_record(std::path::Path::new(&part_path)).await?;
+        let mismatch = match expected_len {
+            Some(expected) if expected != received => {
+                Some(format!("truncated download ({} of {} bytes)", received, expected))
+            }
+            _ if record.size != received || record.sha256 != hex::encode(hasher.finalize()) => {
+                Some("file on disk doesn't match the downloaded bytes".to_string())
+            }
+            _ => None,
+        };
+        (received, mismatch)
+    };
+    if let Some(mismatch) = mismatch {
+        let _ = tokio::fs::remove_file(&part_path).await;
+        return Err(format!("{}: {}", to, mismatch).into());
+    }
+
+    // Content types from the CDN are unreliable (and `mime_guess` maps some
+    // of them oddly), so trust the bytes first.
+    let ext = match sniff::file(std::path::Path::new(&part_path)).await? {
+        Some(format) => format.extension().to_string(),
+        None => extension_for(ct.as_deref()),
+    };
+    let final_path = if ext.is_empty() {
+        to.to_string()
+    } else {
+        format!("{}.{}", to, ext)
+    };
+    tokio::fs::rename(&part_path, &final_path).await?;
+    tracing::info!(bytes = received, "downloaded {}", final_path);
+    Ok(PathBuf::from(final_path))
+}
+
+pub const PART_EXT: &str = "part";
+
+static SOURCE: Lazy<String> = Lazy::new(|| config::get().cache.source.clone());
+
+/// Name of this service's directory inside each `{hash}` directory. Read
+/// once at startup, like [`CACHEDIR`].
+pub fn source() -> &'static str {
+    &SOURCE
+}
+
+pub fn entry_dir(hash: &str) -> PathBuf {
+    CACHEDIR.join(hash).join(source())
+}
+
+/// What an entry held before a download started, so [`cleanup_incomplete`]
+/// only removes what the download added.
+#[derive(Clone, Debug)]
+pub struct EntrySnapshot {
+    /// `None` if the entry didn't exist yet.
+    files: Option<HashSet<std::ffi::OsString>>,
+    hash_dir_existed: bool,
+}
+
+impl EntrySnapshot {
+    pub async fn take(entry: &std::path::Path) -> std::io::Result<Self> {
+        let hash_dir_existed = match entry.parent() {
+            Some(parent) => tokio::fs::try_exists(parent).await?,
+            None => true,
+        };
+        let mut dir = match tokio::fs::read_dir(entry).await {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(EntrySnapshot { files: None, hash_dir_existed });
+            }
+            Err(e) => return Err(e),
+        };
+        let mut files = HashSet::new();
+        while let Some(file) = dir.next_entry().await? {
+            files.insert(file.file_name());
+        }
+        Ok(EntrySnapshot { files: Some(files), hash_dir_existed })
+    }
+}
+
+/// Removes leftover `.part` files from an entry and anything else that
+/// appeared in it since `before`. Files that were there already, e.g.
+/// downloads from before entries had manifests, are kept. The entry and its
+/// `{hash}` directory are removed only if the download created them and
+/// they're left empty.
+pub async fn cleanup_incomplete(entry: &std::path::Path, before: &EntrySnapshot) -> std::io::Result<()> {
+    let mut dir = match tokio::fs::read_dir(entry).await {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    while let Some(file) = dir.next_entry().await? {
+        let path = file.path();
+        let is_part = path.extension().is_some_and(|ext| ext == PART_EXT);
+        let added = before.files.as_ref().is_none_or(|files| !files.contains(&file.file_name()));
+        if !is_part && !added {
+            continue;
+        }
+        if file.file_type().await?.is_dir() {
+            tokio::fs::remove_dir_all(&path).await?;
+        } else {
+            tokio::fs::remove_file(&path).await?;
+        }
+    }
+    if before.files.is_none() && gc::remove_if_empty(entry).await? && !before.hash_dir_existed
+        && let Some(parent) = entry.parent()
+    {
+        gc::remove_if_empty(parent).await?;
+    }
+    Ok(())
+}
+
+const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+/// Set on answers that come from the job an earlier request with the same
+/// `Idempotency-Key` started.
+const IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+
+/// `[cache] dir` as it was at startup; moving the cache needs a restart.
+static CACHEDIR: Lazy<PathBuf> = Lazy::new(|| {
+    config::get().cache.dir.clone().unwrap_or_else(|| {
+        let mut path = env::current_dir().unwrap();
+        path.push("TRICACHE");
+        path
+    })
+});
+
+/// Downloads the track with the request's cookie, or with accounts from the
+/// pool when it has none.
+pub async fn save_by_id(params: &DownloadZVUK) -> Result<u64, Box<dyn Error>> {
+    with_account(&params.auth_cookie, |auth_cookie| {
+        let params = DownloadZVUK { auth_cookie, ..params.clone() };
+        async move { save_with_cookie(&params).await }
+    })
+    .await
+}
+
+/// Runs `f` with `cookie`, or when it's empty with accounts from the pool,
+/// moving on to the next account when one is benched.
+async fn with_account<T, F, Fut>(cookie: &str, f: F) -> Result<T, Box<dyn Error>>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<T, Box<dyn Error>>>,
+{
+    if !cookie.is_empty() {
+        return f(cookie.to_string()).await;
+    }
+    let mut tried = Vec::new();
+    let mut last = None;
+    while let Some(account) = accounts::pick(&tried) {
+        let e = match f(account.cookie).await {
+            Ok(done) => {
+                accounts::succeeded(&account.name);
+                return Ok(done);
+            }
+            Err(e) => e,
+        };
+        let category = failure::Failure::classify(e.as_ref()).category;
+        if !accounts::should_bench(category) {
+            accounts::failed(&account.name);
+            return Err(e);
+        }
+        accounts::bench(&account.name, &e.to_string());
+        last = Some((category, e.to_string()));
+        tried.push(account.name);
+    }
+    let (category, last_error) = match last {
+        Some((category, e)) => (category, Some(e)),
+        None => (failure::Category::RateLimited, None),
+    };
+    Err(accounts::PoolExhausted { category, last_error }.into())
+}
+
+/// Downloads the track, first swapping it for (or adding) its explicit or
+/// clean counterpart as the explicit policy asks.
+async fn save_with_cookie(params: &DownloadZVUK) -> Result<u64, Box<dyn Error>> {
+    let policy = params.explicit_policy.unwrap_or(config::get().explicit_policy);
+    if policy == config::ExplicitPolicy::AsRequested {
+        return save_track(params, None, None).await;
+    }
+    let cookie = Some(params.auth_cookie.as_str());
+    let requested = zvuk::track_meta(&params.id, cookie).await.map_err(|e| {
+        tracing::warn!("couldn't look up track {}, ignoring explicit policy: {}", params.id, e);
+    });
+    let Ok(requested) = requested else {
+        return save_track(params, None, None).await;
+    };
+    let alternate = zvuk::counterpart(&requested, cookie).await.unwrap_or_else(|e| {
+        tracing::warn!("couldn't look for another version of track {}: {}", params.id, e);
+        None
+    });
+    let variant = |alternate_id: Option<&str>, alternate_hash: Option<&str>| manifest::Variant {
+        policy,
+        requested_id: params.id.clone(),
+        alternate_id: alternate_id.map(String::from),
+        alternate_hash: alternate_hash.map(String::from),
+    };
+
+    let Some(alternate) = alternate else {
+        return save_track(params, Some(requested), Some(variant(None, None))).await;
+    };
+    let swap = match policy {
+        config::ExplicitPolicy::PreferExplicit => requested.explicit == Some(false),
+        config::ExplicitPolicy::PreferClean => requested.explicit == Some(true),
+        _ => false,
+    };
+    if swap {
+        let params = DownloadZVUK { id: alternate.id.clone(), ..params.clone() };
+        return save_track(&params, Some(alternate), Some(variant(Some(&requested.id), None))).await;
+    }
+    if policy != config::ExplicitPolicy::Both {
+        return save_track(params, Some(requested), Some(variant(Some(&alternate.id), None))).await;
+    }
+
+    let alternate_hash = tenant::track_hash(&alternate.id);
+    let mut bytes = save_track(
+        params,
+        Some(requested),
+        Some(variant(Some(&alternate.id), Some(&alternate_hash))),
+    )
+    .await?;
+    let alternate_params = DownloadZVUK {
+        id: alternate.id.clone(),
+        hash: alternate_hash.clone(),
+        ..params.clone()
+    };
+    bytes += save_track(
+        &alternate_params,
+        Some(alternate),
+        Some(variant(Some(&params.id), Some(&params.hash))),
+    )
+    .await?;
+    Ok(bytes)
+}
+
+/// Downloads one track into its entry; `meta` is fetched if not given.
+async fn save_track(
+    params: &DownloadZVUK,
+    meta: Option<zvuk::TrackMeta>,
+    variant: Option<manifest::Variant>,
+) -> Result<u64, Box<dyn Error>> {
+    let DownloadZVUK { id, auth_cookie, hash, transcode, .. } = params;
+    let urls = zvuk::stream_urls(id, auth_cookie).await?;
+
+    let entry = entry_dir(hash);
+    tokio::fs::create_dir_all(&entry).await.unwrap();
+
+    let targets: Vec<(String, &String)> = config::get()
+        .qualities
+        .iter()
+        .filter_map(|quality| {
+            let url = urls.get(quality.index())?;
+            Some((entry.join(quality.as_str()).to_str().unwrap().to_string(), url))
+        })
+        .collect();
+    // Either way `written` keeps the configured order; transcodes use the first.
+    let mut written = if config::get().download.concurrent_qualities {
+        try_join_all(targets.iter().map(|(path, url)| dl_file(url, path)))
+            .await
+            .map_err(|e| e as Box<dyn Error>)?
+    } else {
+        let mut written = Vec::new();
+        for (path, url) in &targets {
+            // Earlier qualities may have used up the space a size-less
+            // response can't be checked against.
+            disk::check(0)?;
+            written.push(dl_file(url, path).await.map_err(|e| e as Box<dyn Error>)?);
+        }
+        written
+    };
+
+    let meta = match meta {
+        Some(meta) => Some(meta),
+        None => zvuk::track_meta(id, Some(auth_cookie)).await.ok(),
+    };
+    if let Some(meta) = &meta {
+        metadata::write(&entry, meta, &written).await?;
+    }
+    if params.include_lyrics {
+        let lyrics = zvuk::lyrics(id, Some(auth_cookie))
+            .await
+            .unwrap_or_else(|e| {
+                tracing::warn!("couldn't fetch lyrics for track {}: {}", id, e);
+                None
+            });
+        match lyrics {
+            Some(lyrics) => metadata::write_lyrics(&entry, &lyrics).await?,
+            None => tracing::info!("no lyrics for track {}", id),
+        }
+    }
+
+    if let (Some(opts), Some(src)) = (transcode, written.first()) {
+        disk::check(0)?;
+        written.push(transcode::run(src, &entry, opts).await?);
+    }
+    if params.normalize {
+        loudness::write(&entry, &written).await?;
+    }
+
+    let mut files = Vec::new();
+    for path in &written {
+        let record = manifest::file_record(path).await?;
+        dedupe::share(path, &record).await?;
+        files.push(record);
+    }
+    let mut manifest = manifest::Manifest::new(id, hash, files, meta.as_ref());
+    manifest.variant = variant;
+    manifest::export(&entry, &manifest).await?;
+
+    let isrc = meta
+        .and_then(|m| m.isrc)
+        .map(|isrc| isrc.to_ascii_uppercase());
+    if let Err(e) = aliases::store().record(hash, id, isrc.as_deref()) {
+        tracing::warn!("couldn't record alias {} -> {}: {}", hash, id, e);
+    }
+    Ok(manifest.files.iter().map(|f| f.size).sum())
+}
+
+
+fn job_owner<'a>(
+    principal: &'a Option<Extension<auth::Principal>>,
+    client: &'a Option<Extension<auth::ClientId>>,
+    request_id: &'a Option<Extension<RequestId>>,
+) -> jobs::Owner<'a> {
+    jobs::Owner {
+        client: client.as_ref().map(|Extension(c)| c.0.as_str()),
+        tenant: principal.as_ref().and_then(|Extension(p)| p.tenant()),
+        request_id: request_id.as_ref().and_then(|Extension(id)| id.header_value().to_str().ok()),
+    }
+}
+
+/// Span for one HTTP request, keyed by the `X-Request-Id` assigned (or
+/// passed through) by [`SetRequestIdLayer`].
+fn request_span(req: &axum::http::Request<axum::body::Body>) -> tracing::Span {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("-");
+    tracing::info_span!("request", request_id, method = %req.method(), uri = %req.uri())
+}
+
+/// Canonical cache key for a client-supplied hash, per the caller's tenant.
+fn canonical_hash(principal: &Option<Extension<auth::Principal>>, hash: &str) -> Result<String, String> {
+    tenant::scheme(principal.as_ref().map(|Extension(p)| p)).canonicalize(hash)
+}
+
+/// Maps a refused submission onto its response: 503 while draining, 429 with
+/// `Retry-After` for client limits, 507 when the cache is full, 500 otherwise.
+fn submit_error_response(e: jobs::SubmitError) -> axum::response::Response {
+    let error = e.to_string();
+    match e {
+        jobs::SubmitError::ShuttingDown => ApiError::new(StatusCode::SERVICE_UNAVAILABLE, error),
+        // No way to know when a running job frees up; ask for a short back-off.
+        jobs::SubmitError::TooManyJobs => {
+            ApiError::new(StatusCode::TOO_MANY_REQUESTS, error).retry_after(Duration::from_secs(10))
+        }
+        jobs::SubmitError::QuotaExceeded { retry_after } => {
+            ApiError::new(StatusCode::TOO_MANY_REQUESTS, error).retry_after(retry_after)
+        }
+        jobs::SubmitError::KeyReused => ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, error),
+        // The queue looks at free space again this often.
+        jobs::SubmitError::StorageFull(_) => {
+            ApiError::new(StatusCode::INSUFFICIENT_STORAGE, error).retry_after(jobs::DISK_RECHECK)
+        }
+        jobs::SubmitError::Store(_) => ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, error),
+    }
+    .into_response()
+}
+
+/// The `Idempotency-Key` header, or `idempotency_key` from the payload.
+fn idempotency_key(headers: &HeaderMap, payload: &DownloadZVUK) -> Result<Option<String>, String> {
+    let key = match headers.get(IDEMPOTENCY_KEY) {
+        Some(value) => Some(value.to_str().map_err(|_| "Idempotency-Key must be visible ASCII")?.to_string()),
+        None => payload.idempotency_key.clone(),
+    };
+    match key {
+        Some(key) if key.is_empty() || key.len() > 255 => Err("Idempotency-Key must be 1 to 255 characters".to_string()),
+        key => Ok(key),
+    }
+}
+
+fn mark_replayed(mut response: axum::response::Response, submitted: jobs::Submitted) -> axum::response::Response {
+    if let jobs::Submitted::Replayed(_) = submitted {
+        response.headers_mut().insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+    }
+    response
+}
+
+/// Waits for a job an earlier `/dl` started and returns how it ended. The
+/// wait stops a second short of the route timeout, so a job that's still
+/// going is reported as such rather than as a timed-out request.
+async fn replayed_outcome(id: i64) -> jobs::Outcome {
+    let routes = &config::get().routes;
+    let wait = routes.policy(&routes.download, "/dl").timeout().saturating_sub(Duration::from_secs(1));
+    match jobs::queue().wait_settled(id, wait).await {
+        Ok(Some(job)) if job.state == jobs::JobState::Done => Ok(0),
+        Ok(Some(job)) if job.state.is_settled() => {
+            Err(job.error.unwrap_or_else(|| format!("job {} is {}", id, job.state.as_str())))
+        }
+        Ok(Some(_)) => Err(format!("job {} is still running; send the request again to keep waiting", id)),
+        Ok(None) => Err("job was dropped".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// The error for a `/dl` job that didn't succeed, coded after its state and
+/// the category of its last attempt.
+fn job_error(id: i64, error: String) -> ApiError {
+    let store = &jobs::queue().store;
+    let Ok(Some(job)) = store.get(id) else {
+        return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, error);
+    };
+    match job.state {
+        jobs::JobState::Cancelled => ApiError::new(StatusCode::CONFLICT, error),
+        // Outlasted the route's timeout or was interrupted by shutdown; it
+        // finishes later, so resubmitting with the same key picks it up.
+        jobs::JobState::Queued | jobs::JobState::Running => ApiError::new(StatusCode::GATEWAY_TIMEOUT, error),
+        _ => match store.attempts(id).ok().and_then(|a| a.last().and_then(|a| a.category)) {
+            Some(category) => ApiError::coded(category.into(), error),
+            None => ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, error),
+        },
+    }
+}
+
+async fn download(
+    principal: Option<Extension<auth::Principal>>,
+    client: Option<Extension<auth::ClientId>>,
+    request_id: Option<Extension<RequestId>>,
+    deadline: Option<Extension<limits::Deadline>>,
+    headers: HeaderMap,
+    Valid(mut payload): Valid<DownloadZVUK>,
+) -> axum::response::Response {
+    if let Some(Extension(principal)) = &principal
+        && !principal.may_download(&payload.id)
+    {
+        return ApiError::new(StatusCode::FORBIDDEN, "token doesn't cover this track").into_response();
+    }
+    payload.hash = match canonical_hash(&principal, &payload.hash) {
+        Ok(hash) => hash,
+        Err(e) => {
+            return ApiError::new(StatusCode::BAD_REQUEST, e).into_response();
+        }
+    };
+    if let Err(e) = zvuk::check_proxy(payload.proxy.as_deref()) {
+        return ApiError::new(StatusCode::BAD_REQUEST, e).into_response();
+    }
+    if let Err(e) = accounts::check_cookie(&payload.auth_cookie) {
+        return ApiError::new(StatusCode::BAD_REQUEST, e).into_response();
+    }
+    if payload.not_before.is_some() {
+        let error = "not_before can't be used with /dl, which waits for the download; use /jobs".to_string();
+        return ApiError::new(StatusCode::BAD_REQUEST, error).into_response();
+    }
+    payload.priority.get_or_insert(jobs::Priority::High);
+    let key = match idempotency_key(&headers, &payload) {
+        Ok(key) => key,
+        Err(error) => return ApiError::new(StatusCode::BAD_REQUEST, error).into_response(),
+    };
+    let owner = job_owner(&principal, &client, &request_id);
+    let (submitted, result) = match jobs::queue().submit_waiting(&payload, &owner, key.as_deref()) {
+        Ok((submitted, Some(done))) => {
+            let guard = jobs::CancelGuard::new(submitted.id(), deadline.map(|Extension(d)| d));
+            let outcome = done.await.unwrap_or_else(|_| Err("job was dropped".to_string()));
+            guard.disarm();
+            (submitted, outcome)
+        }
+        Ok((submitted, None)) => (submitted, replayed_outcome(submitted.id()).await),
+        Err(e) => return submit_error_response(e),
+    };
+    let response = match result {
+        Ok(_) if payload.checksums => match manifest::read(&entry_dir(&payload.hash)).await {
+            Ok(manifest) => axum::Json(json!({ "ok": true, "error": "", "files": manifest.files })).into_response(),
+            Err(e) => {
+                let error = format!("downloaded, but couldn't read the manifest: {}", e);
+                ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, error).into_response()
+            }
+        },
+        Ok(_inner) => (
+            StatusCode::OK,
+            axum::Json(IsOK { ok: true, error: "".to_string() }),
+        )
+            .into_response(),
+        Err(e) => job_error(submitted.id(), format!("save_best_medium_low failed: {}", e)).into_response(),
+    };
+    mark_replayed(response, submitted)
+}
+
+async fn signing_key() -> axum::response::Response {
+    match manifest::verifying_key() {
+        Some(key) => {
+            axum::Json(json!({ "algorithm": "ed25519", "public_key": hex::encode(key.to_bytes()) })).into_response()
+        }
+        None => ApiError::new(StatusCode::NOT_FOUND, "manifest signing is not configured").into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct SearchParams {
+    q: String,
+    #[serde(rename = "type", default)]
+    kind: zvuk::SearchType,
+    limit: Option<u32>,
+    cursor: Option<String>,
+}
+
+async fn search(Query(params): Query<SearchParams>, headers: HeaderMap) -> axum::response::Response {
+    let cookie = headers.get("x-zvuk-cookie").and_then(|h| h.to_str().ok());
+    let proxy = headers.get("x-zvuk-proxy").and_then(|h| h.to_str().ok()).map(str::to_string);
+    if let Err(e) = zvuk::check_proxy(proxy.as_deref()) {
+        return ApiError::new(StatusCode::BAD_REQUEST, e).into_response();
+    }
+    let limit = params.limit.unwrap_or(20).clamp(1, 100);
+    let found = zvuk::with_proxy(
+        proxy,
+        zvuk::search(&params.q, params.kind, limit, params.cursor.as_deref(), cookie),
+    );
+    match found.await {
+        Ok(page) => axum::Json(page).into_response(),
+        Err(e) => ApiError::upstream(&*e).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct StreamParams {
+    #[serde(default)]
+    quality: config::Quality,
+    /// Hash to store the stream under as it's proxied.
+    cache: Option<String>,
+}
+
+/// Proxies a track's CDN stream, passing `Range` through so players can seek.
+async fn stream_track(
+    principal: Option<Extension<auth::Principal>>,
+    Path(id): Path<String>,
+    Query(params): Query<StreamParams>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    if let Some(Extension(principal)) = &principal
+        && !principal.may_download(&id)
+    {
+        return ApiError::new(StatusCode::FORBIDDEN, "token doesn't cover this track").into_response();
+    }
+    // Without the header, the pool's accounts are used, like for downloads.
+    let cookie = headers.get("x-zvuk-cookie").and_then(|h| h.to_str().ok()).unwrap_or_default().to_string();
+    if cookie.is_empty() && !accounts::configured() {
+        return ApiError::new(StatusCode::BAD_REQUEST, "X-Zvuk-Cookie header is required; no [accounts.pool] is configured").into_response();
+    }
+    let proxy = headers.get("x-zvuk-proxy").and_then(|h| h.to_str().ok()).map(str::to_string);
+    if let Err(e) = zvuk::check_proxy(proxy.as_deref()) {
+        return ApiError::new(StatusCode::BAD_REQUEST, e).into_response();
+    }
+    let (index, name) = (params.quality.index(), params.quality.as_str());
+    let tee = match params.cache.map(|hash| canonical_hash(&principal, &hash)).transpose() {
+        Ok(hash) => hash.map(|hash| stream::Tee { track_id: id.clone(), entry: entry_dir(&hash), hash, name }),
+        Err(e) => return ApiError::new(StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    // A job downloading into the entry owns it; stream without caching.
+    let tee = tee.filter(|tee| !jobs::running_hashes().contains(&tee.hash));
+
+    zvuk::with_proxy(proxy, async {
+        let url = with_account(&cookie, |cookie| {
+            let id = &id;
+            async move { zvuk::stream_urls(id, &cookie).await }
+        })
+        .await
+        .map_err(|e| ApiError::upstream(&*e));
+        let url = match url.and_then(|urls| {
+            urls.into_iter()
+                .nth(index)
+                .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, format!("no {} stream for this track", name)))
+        }) {
+            Ok(url) => url,
+            Err(e) => return e.into_response(),
+        };
+        match stream::proxy(&url, &headers, tee).await {
+            Ok(res) => res,
+            Err(e) => ApiError::upstream(&e).into_response(),
+        }
+    })
+    .await
+}
+
+fn aliases_response(found: rusqlite::Result<Vec<aliases::Alias>>) -> axum::response::Response {
+    match found {
+        Ok(list) if list.is_empty() => ApiError::new(StatusCode::NOT_FOUND, "no known aliases").into_response(),
+        Ok(list) => axum::Json(json!({ "aliases": list })).into_response(),
+        Err(e) => ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn resolve_hash(
+    principal: Option<Extension<auth::Principal>>,
+    Path(hash): Path<String>,
+) -> axum::response::Response {
+    match canonical_hash(&principal, &hash) {
+        Ok(hash) => aliases_response(aliases::store().by_hash(&hash)),
+        Err(e) => ApiError::new(StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
+
+async fn cover_art(
+    principal: Option<Extension<auth::Principal>>,
+    Path((hash, size)): Path<(String, String)>,
+) -> axum::response::Response {
+    let hash = match canonical_hash(&principal, &hash) {
+        Ok(hash) => hash,
+        Err(e) => return ApiError::new(StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    if !config::get().art.sizes.contains(&size) {
+        return ApiError::new(StatusCode::NOT_FOUND, format!("art size {} isn't configured", size)).into_response();
+    }
+    match tokio::fs::read(entry_dir(&hash).join(metadata::cover_file(&size))).await {
+        Ok(bytes) => ([(axum::http::header::CONTENT_TYPE, "image/jpeg")], bytes).into_response(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            ApiError::new(StatusCode::NOT_FOUND, "no cover stored for this entry").into_response()
+        }
+        Err(e) => ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// Serves a stored quality (`best`, `mid` or `transcoded`) with `Range`
+/// support, labelled with the format its bytes actually are rather than the
+/// one its extension claims.
+async fn audio(
+    principal: Option<Extension<auth::Principal>>,
+    Path((hash, quality)): Path<(String, String)>,
+    req: axum::extract::Request,
+) -> axum::response::Response {
+    let hash = match canonical_hash(&principal, &hash) {
+        Ok(hash) => hash,
+        Err(e) => return ApiError::new(StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let entry = entry_dir(&hash);
+    let stored = metadata::audio_files(&entry).await.ok().and_then(|files| {
+        files
+            .into_iter()
+            .find(|path| path.file_stem().and_then(|s| s.to_str()) == Some(quality.as_str()))
+    });
+    let Some(mut path) = stored else {
+        let error = format!("no {} file stored for this entry", quality);
+        return ApiError::new(StatusCode::NOT_FOUND, error).into_response();
+    };
+    let format = match sniff::file(&path).await {
+        Ok(format) => format,
+        Err(e) => {
+            return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        }
+    };
+    if let Some(format) = format
+        && config::get().cache.fix_extensions
+        && path.extension().and_then(|e| e.to_str()) != Some(format.extension())
+    {
+        match fix_extension(&hash, &entry, &path, format).await {
+            Ok(Some(renamed)) => path = renamed,
+            Ok(None) => {}
+            Err(e) => tracing::warn!("couldn't rename {}: {}", path.display(), e),
+        }
+    }
+    let mime = match format {
+        Some(format) => format.mime().parse().unwrap(),
+        None => mime_guess::from_path(&path).first_or_octet_stream(),
+    };
+    match tower_http::services::ServeFile::new_with_mime(&path, &mime).try_call(req).await {
+        Ok(res) => res.map(axum::body::Body::new),
+        Err(e) => ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// Gives a stored file the extension of its real format and updates the
+/// manifest to match, unless a job is downloading into the entry.
+async fn fix_extension(
+    hash: &str,
+    entry: &std::path::Path,
+    path: &std::path::Path,
+    format: sniff::Format,
+) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    let _entries = manifest::lock_entries().await;
+    if jobs::running_hashes().contains(hash) {
+        return Ok(None);
+    }
+    let renamed = path.with_extension(format.extension());
+    let name = |p: &std::path::Path| p.file_name().and_then(|n| n.to_str()).map(String::from);
+    let (old_name, new_name) = (name(path), name(&renamed));
+    tokio::fs::rename(path, &renamed).await?;
+    tracing::info!("renamed {} to {}", path.display(), renamed.display());
+    let manifest = manifest::read(entry).await.ok();
+    if let Some(mut manifest) = manifest {
+        for file in manifest.files.iter_mut().filter(|f| Some(&f.name) == old_name.as_ref()) {
+            file.name = new_name.clone().unwrap_or_default();
+        }
+        manifest::export(entry, &manifest).await?;
+    }
+    Ok(Some(renamed))
+}
+
+async fn compare_entry(
+    principal: Option<Extension<auth::Principal>>,
+    Path(hash): Path<String>,
+) -> axum::response::Response {
+    let hash = match canonical_hash(&principal, &hash) {
+        Ok(hash) => hash,
+        Err(e) => return ApiError::new(StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let entry = entry_dir(&hash);
+    if !entry.is_dir() {
+        return ApiError::new(StatusCode::NOT_FOUND, "no such entry").into_response();
+    }
+    match compare::run(&entry).await {
+        Ok(report) => axum::Json(report).into_response(),
+        Err(e) => ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn resolve_track(Path(id): Path<String>) -> axum::response::Response {
+    aliases_response(aliases::store().by_track(&id))
+}
+
+async fn resolve_isrc(Path(isrc): Path<String>) -> axum::response::Response {
+    aliases_response(aliases::store().by_isrc(&isrc.to_ascii_uppercase()))
+}
+
+#[derive(Serialize)]
+struct StoredFile {
+    name: String,
+    /// `best`, `mid` or `transcoded`.
+    quality: String,
+    /// The file's extension, e.g. `flac` or `mp3`.
+    format: String,
+    size: u64,
+}
+
+#[derive(Serialize)]
+struct CachedEntry {
+    hash: String,
+    updated_at: i64,
+    qualities: Vec<String>,
+    files: Vec<StoredFile>,
+}
+
+/// Tells whether a track is cached under any hash, so clients can skip
+/// downloads they already have; unfinished jobs for it are listed too.
+async fn lookup_track(
+    principal: Option<Extension<auth::Principal>>,
+    Path(id): Path<String>,
+) -> axum::response::Response {
+    let known = match aliases::store().by_track(&id) {
+        Ok(known) => known,
+        Err(e) => {
+            return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        }
+    };
+    let mut entries = Vec::new();
+    for alias in known {
+        // The alias outlives a purge, and the hash may since hold another track.
+        let Ok(manifest) = manifest::read(&entry_dir(&alias.hash)).await else {
+            continue;
+        };
+        let requested = manifest.variant.as_ref().map(|v| v.requested_id.as_str());
+        if manifest.id != id && requested != Some(id.as_str()) {
+            continue;
+        }
+        let files: Vec<StoredFile> = manifest
+            .files
+            .into_iter()
+            .filter_map(|f| {
+                let (stem, ext) = f.name.split_once('.')?;
+                matches!(stem, "best" | "mid" | "transcoded").then(|| StoredFile {
+                    quality: stem.to_string(),
+                    format: ext.to_string(),
+                    size: f.size,
+                    name: f.name.clone(),
+                })
+            })
+            .collect();
+        if files.is_empty() {
+            continue;
+        }
+        entries.push(CachedEntry {
+            hash: alias.hash,
+            updated_at: alias.updated_at,
+            qualities: files.iter().map(|f| f.quality.clone()).collect(),
+            files,
+        });
+    }
+    entries.sort_by_key(|e| std::cmp::Reverse(e.updated_at));
+
+    let tenant = principal.as_ref().and_then(|Extension(p)| p.tenant()).map(str::to_string);
+    // Hashes are the tenants' own cache keys, so a tenant only learns of the
+    // entries its jobs downloaded, like it only sees its own jobs.
+    if let Some(tenant) = &tenant {
+        let mut own = Vec::new();
+        for entry in entries {
+            let filter = jobs::JobFilter {
+                hash: Some(entry.hash.clone()),
+                tenant: Some(tenant.clone()),
+                limit: Some(1),
+                ..Default::default()
+            };
+            match jobs::queue().store.list(&filter) {
+                Ok(found) if !found.is_empty() => own.push(entry),
+                Ok(_) => {}
+                Err(e) => {
+                    return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+                }
+            }
+        }
+        entries = own;
+    }
+    let filter = jobs::JobFilter { track_id: Some(id.clone()), tenant, ..Default::default() };
+    let pending: Vec<jobs::Job> = match jobs::queue().store.list(&filter) {
+        Ok(found) => found.into_iter().filter(|job| !job.state.is_settled()).collect(),
+        Err(e) => {
+            return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        }
+    };
+    axum::Json(json!({
+        "track_id": id,
+        "cached": !entries.is_empty(),
+        "entries": entries,
+        "pending": pending,
+    }))
+    .into_response()
+}
+
+#[derive(Deserialize)]
+struct HydrateRequest {
+    auth_cookie: Option<String>,
+    /// Named proxy from `[proxies]`.
+    proxy: Option<String>,
+    /// Track IDs of entries that have neither a manifest nor an alias.
+    #[serde(default)]
+    track_ids: HashMap<String, String>,
+}
+
+impl Validate for HydrateRequest {
+    fn validate(&self) -> Result<(), String> {
+        if self.track_ids.len() > validate::MAX_IDS {
+            let (len, max) = (self.track_ids.len(), validate::MAX_IDS);
+            return Err(format!("track_ids has {} entries; at most {} are allowed", len, max));
+        }
+        match self.track_ids.iter().find(|(_, id)| id.trim().is_empty()) {
+            Some((hash, _)) => Err(format!("track_ids[{:?}] must not be empty", hash)),
+            None => Ok(()),
+        }
+    }
+}
+
+async fn start_hydration(
+    principal: Option<Extension<auth::Principal>>,
+    Valid(req): Valid<HydrateRequest>,
+) -> axum::response::Response {
+    if let Err(e) = zvuk::check_proxy(req.proxy.as_deref()) {
+        return ApiError::new(StatusCode::BAD_REQUEST, e).into_response();
+    }
+    let mut track_ids = HashMap::new();
+    for (hash, id) in req.track_ids {
+        match canonical_hash(&principal, &hash) {
+            Ok(hash) => track_ids.insert(hash, id),
+            Err(e) => return ApiError::new(StatusCode::BAD_REQUEST, e).into_response(),
+        };
+    }
+    {
+        let mut report = metadata::HYDRATION.lock().unwrap();
+        if report.as_ref().is_some_and(|r| r.running) {
+            return ApiError::new(StatusCode::CONFLICT, "hydration is already running").into_response();
+        }
+        *report = Some(metadata::HydrationReport {
+            running: true,
+            started_at: unix_now(),
+            ..Default::default()
+        });
+    }
+    tokio::spawn(zvuk::with_proxy(req.proxy, metadata::hydrate(req.auth_cookie, track_ids)));
+    (StatusCode::ACCEPTED, axum::Json(IsOK { ok: true, error: "".to_string() })).into_response()
+}
+
+#[derive(Deserialize)]
+struct ValidateRequest {
+    auth_cookie: String,
+    proxy: Option<String>,
+}
+
+impl Validate for ValidateRequest {
+    fn validate(&self) -> Result<(), String> {
+        validate::non_empty("auth_cookie", &self.auth_cookie)
+    }
+}
+
+/// Checks a session cookie and, if Zvuk accepts it, resumes the caller's jobs
+/// parked on expired credentials using that cookie.
+async fn validate_session(
+    client: Option<Extension<auth::ClientId>>,
+    Valid(req): Valid<ValidateRequest>,
+) -> axum::response::Response {
+    if let Err(e) = zvuk::check_proxy(req.proxy.as_deref()) {
+        return ApiError::new(StatusCode::BAD_REQUEST, e).into_response();
+    }
+    match zvuk::with_proxy(req.proxy.clone(), zvuk::validate(&req.auth_cookie)).await {
+        Ok(false) => axum::Json(json!({ "valid": false, "resumed": [] })).into_response(),
+        Ok(true) => {
+            let resumed = match &client {
+                Some(Extension(client)) => jobs::queue().resume_parked(&client.0, &req.auth_cookie),
+                None => Ok(Vec::new()),
+            };
+            match resumed {
+                Ok(resumed) => axum::Json(json!({ "valid": true, "resumed": resumed })).into_response(),
+                Err(e) => submit_error_response(e),
+            }
+        }
+        Err(e) => ApiError::upstream(&*e).into_response(),
+    }
+}
+
+impl Validate for diagnose::DiagnoseRequest {
+    fn validate(&self) -> Result<(), String> {
+        match &self.track_id {
+            Some(id) => validate::non_empty("track_id", id),
+            None => Ok(()),
+        }
+    }
+}
+
+async fn diagnose(req: Option<Valid<diagnose::DiagnoseRequest>>) -> axum::response::Response {
+    let req = req.map(|Valid(req)| req).unwrap_or_default();
+    match diagnose::run(req).await {
+        Ok(report) => axum::Json(report).into_response(),
+        Err(e) => ApiError::new(StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
+
+async fn collect_garbage() -> axum::response::Response {
+    match gc::sweep().await {
+        Ok(report) => axum::Json(report).into_response(),
+        Err(e) => ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// Re-reads the config file; settings only read at startup are listed in
+/// `restart_required` if they changed.
+async fn reload_config() -> axum::response::Response {
+    match config::reload() {
+        Ok(restart_required) => {
+            tracing::info!(?restart_required, "config reloaded");
+            axum::Json(json!({ "ok": true, "restart_required": restart_required })).into_response()
+        }
+        Err(error) => ApiError::new(StatusCode::BAD_REQUEST, error).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct StatsParams {
+    /// Days of history to include; 30 if absent.
+    days: Option<u32>,
+}
+
+/// Failure categories listed in `/stats`.
+const TOP_FAILURES: usize = 10;
+
+/// Aggregate figures for operators: jobs per day, success rate, the most
+/// common failure categories, cache usage and queue depth.
+async fn stats(Query(params): Query<StatsParams>) -> axum::response::Response {
+    let days = params.days.unwrap_or(30).clamp(1, 366);
+    let now = unix_now() as i64;
+    // From the start of the first day covered, in UTC.
+    let since = (now / 86400 - (days as i64 - 1)) * 86400;
+    let queue = jobs::queue();
+    let totals = match queue.store.totals(since, TOP_FAILURES) {
+        Ok(totals) => totals,
+        Err(e) => {
+            return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        }
+    };
+    let usage = match gc::usage().await {
+        Ok(usage) => usage,
+        Err(e) => {
+            return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        }
+    };
+    let done: u64 = totals.by_day.iter().map(|d| d.done).sum();
+    let failed: u64 = totals.by_day.iter().map(|d| d.failed).sum();
+    let rate = |n: u64| (done + failed > 0).then(|| n as f64 / (done + failed) as f64);
+    axum::Json(json!({
+        "days": days,
+        "since": since,
+        "jobs": {
+            "done": done,
+            "failed": failed,
+            "success_rate": rate(done),
+            "failure_rate": rate(failed),
+            "bytes": totals.by_day.iter().map(|d| d.bytes).sum::<u64>(),
+        },
+        "by_day": totals.by_day,
+        "top_failures": totals.top_failures,
+        "cache": usage,
+        "disk": {
+            "free_bytes": disk::available(&CACHEDIR).ok().flatten(),
+            "reserve_bytes": config::get().cache.min_free_bytes,
+        },
+        "queue": {
+            "running": queue.running_count(),
+            "queued": queue.depth(),
+            "next_scheduled": queue.next_scheduled(),
+            "paused": queue.paused(),
+        },
+    }))
+    .into_response()
+}
+
+/// Health of the `[accounts.pool]` accounts; cookies are never shown.
+async fn account_pool() -> axum::response::Response {
+    let accounts = accounts::health();
+    let available = accounts.iter().filter(|a| a.available).count();
+    axum::Json(json!({
+        "selection": config::get().accounts.selection,
+        "available": available,
+        "accounts": accounts,
+    }))
+    .into_response()
+}
+
+async fn lyrics(Path(id): Path<String>, headers: HeaderMap) -> axum::response::Response {
+    let cookie = headers.get("x-zvuk-cookie").and_then(|h| h.to_str().ok());
+    match zvuk::lyrics(&id, cookie).await {
+        Ok(Some(lyrics)) => axum::Json(json!({ "id": id, "synced": lyrics.synced, "lyrics": lyrics.text })).into_response(),
+        Ok(None) => ApiError::new(StatusCode::NOT_FOUND, "no lyrics for this track").into_response(),
+        Err(e) => ApiError::upstream(&*e).into_response(),
+    }
+}
+
+async fn hydration_status() -> axum::response::Response {
+    match metadata::HYDRATION.lock().unwrap().clone() {
+        Some(report) => axum::Json(report).into_response(),
+        None => ApiError::new(StatusCode::NOT_FOUND, "hydration has not been run").into_response(),
+    }
+}
+
+async fn purge(
+    principal: Option<Extension<auth::Principal>>,
+    Path(hash): Path<String>,
+) -> axum::response::Response {
+    let hash = match canonical_hash(&principal, &hash) {
+        Ok(hash) => hash,
+        Err(e) => {
+            return ApiError::new(StatusCode::BAD_REQUEST, e).into_response();
+        }
+    };
+    if jobs::running_hashes().contains(&hash) {
+        return ApiError::new(StatusCode::CONFLICT, "a job is downloading into this entry").into_response();
+    }
+    // Only this service's directory; other sources sharing `{hash}` keep theirs.
+    match tokio::fs::remove_dir_all(entry_dir(&hash)).await {
+        Ok(()) => {
+            if let Err(e) = gc::remove_if_empty(&CACHEDIR.join(&hash)).await {
+                tracing::warn!("couldn't remove {}: {}", hash, e);
+            }
+            (
+                StatusCode::OK,
+                axum::Json(IsOK { ok: true, error: "".to_string() }),
+            )
+                .into_response()
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            ApiError::new(StatusCode::NOT_FOUND, "no such cache entry").into_response()
+        }
+        Err(e) => ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct VerifyRequest {
+    /// Re-download the entry if any file fails the check.
+    #[serde(default)]
+    redownload: bool,
+    auth_cookie: Option<String>,
+}
+
+impl Validate for VerifyRequest {
+    fn validate(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Re-checks an entry's files against its manifest and, if asked, removes the
+/// bad ones and enqueues a fresh download of the track (or rewrites a
+/// collection's playlist).
+async fn verify_entry(
+    principal: Option<Extension<auth::Principal>>,
+    client: Option<Extension<auth::ClientId>>,
+    request_id: Option<Extension<RequestId>>,
+    Path(hash): Path<String>,
+    req: Option<Valid<VerifyRequest>>,
+) -> axum::response::Response {
+    let req = req.map(|Valid(req)| req).unwrap_or_default();
+    let hash = match canonical_hash(&principal, &hash) {
+        Ok(hash) => hash,
+        Err(e) => return ApiError::new(StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let entry = entry_dir(&hash);
+    let manifest = match manifest::read(&entry).await {
+        Ok(manifest) => manifest,
+        Err(_) => {
+            return ApiError::new(StatusCode::NOT_FOUND, "no manifest for this entry").into_response();
+        }
+    };
+    let checks = match manifest::verify(&entry, &manifest).await {
+        Ok(checks) => checks,
+        Err(e) => {
+            return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        }
+    };
+    let intact = checks.iter().all(|c| c.status == manifest::FileStatus::Ok);
+    if intact || !req.redownload {
+        return axum::Json(json!({ "ok": intact, "files": checks })).into_response();
+    }
+    // A collection's only file is its playlist, which is rebuilt rather than
+    // downloaded.
+    if manifest.collection.is_some() {
+        if let Err(e) = collection::refresh(&hash).await {
+            return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        }
+        return axum::Json(json!({ "ok": false, "files": checks, "rebuilt": true })).into_response();
+    }
+
+    let auth_cookie = req.auth_cookie.unwrap_or_default();
+    if let Err(e) = accounts::check_cookie(&auth_cookie) {
+        return ApiError::new(StatusCode::BAD_REQUEST, e).into_response();
+    }
+    if let Some(Extension(principal)) = &principal
+        && !principal.may_download(&manifest.id)
+    {
+        return ApiError::new(StatusCode::FORBIDDEN, "token doesn't cover this track").into_response();
+    }
+    for check in checks.iter().filter(|c| c.status != manifest::FileStatus::Ok) {
+        let _ = tokio::fs::remove_file(entry.join(&check.name)).await;
+    }
+    let payload = DownloadZVUK {
+        id: manifest.id,
+        hash,
+        auth_cookie,
+        transcode: None,
+        proxy: None,
+        include_lyrics: false,
+        normalize: false,
+        label: None,
+        // The manifest already holds the version the policy picked.
+        explicit_policy: Some(config::ExplicitPolicy::AsRequested),
+        checksums: false,
+        priority: None,
+        not_before: None,
+        idempotency_key: None,
+        max_kbps: None,
+    };
+    match jobs::queue().submit(&payload, &job_owner(&principal, &client, &request_id), None) {
+        Ok(submitted) => (
+            StatusCode::ACCEPTED,
+            axum::Json(json!({ "ok": false, "files": checks, "job": submitted.id() })),
+        )
+            .into_response(),
+        Err(e) => submit_error_response(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenRequest {
+    scope: Vec<auth::Scope>,
+    ids: Option<Vec<String>>,
+    tenant: Option<String>,
+    ttl_secs: Option<u64>,
+}
+
+impl Validate for TokenRequest {
+    fn validate(&self) -> Result<(), String> {
+        validate::ids("ids", self.ids.as_deref().unwrap_or_default())
+    }
+}
+
+async fn mint_token(Valid(req): Valid<TokenRequest>) -> axum::response::Response {
+    if req.scope.is_empty() {
+        return ApiError::new(StatusCode::BAD_REQUEST, "scope must not be empty").into_response();
+    }
+    let ttl = req.ttl_secs.unwrap_or(15 * 60).clamp(1, auth::MAX_TOKEN_TTL_SECS);
+    if let Some(name) = &req.tenant
+        && !config::get().tenants.contains_key(name)
+    {
+        return ApiError::new(StatusCode::BAD_REQUEST, format!("unknown tenant {}", name)).into_response();
+    }
+    match auth::mint(req.scope, req.ids, req.tenant, ttl) {
+        Ok((token, expires_at)) => {
+            axum::Json(json!({ "token": token, "expires_at": expires_at })).into_response()
+        }
+        Err(e) => ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn submit_job(
+    principal: Option<Extension<auth::Principal>>,
+    client: Option<Extension<auth::ClientId>>,
+    request_id: Option<Extension<RequestId>>,
+    headers: HeaderMap,
+    Valid(mut payload): Valid<DownloadZVUK>,
+) -> axum::response::Response {
+    if let Some(Extension(principal)) = &principal
+        && !principal.may_download(&payload.id)
+    {
+        return ApiError::new(StatusCode::FORBIDDEN, "token doesn't cover this track").into_response();
+    }
+    payload.hash = match canonical_hash(&principal, &payload.hash) {
+        Ok(hash) => hash,
+        Err(e) => {
+            return ApiError::new(StatusCode::BAD_REQUEST, e).into_response();
+        }
+    };
+    if let Err(e) = zvuk::check_proxy(payload.proxy.as_deref()) {
+        return ApiError::new(StatusCode::BAD_REQUEST, e).into_response();
+    }
+    if let Err(e) = accounts::check_cookie(&payload.auth_cookie) {
+        return ApiError::new(StatusCode::BAD_REQUEST, e).into_response();
+    }
+    let key = match idempotency_key(&headers, &payload) {
+        Ok(key) => key,
+        Err(error) => return ApiError::new(StatusCode::BAD_REQUEST, error).into_response(),
+    };
+    match jobs::queue().submit(&payload, &job_owner(&principal, &client, &request_id), key.as_deref()) {
+        Ok(submitted) => {
+            let status = match submitted {
+                jobs::Submitted::New(_) => StatusCode::ACCEPTED,
+                jobs::Submitted::Replayed(_) => StatusCode::OK,
+            };
+            mark_replayed((status, axum::Json(json!({ "id": submitted.id() }))).into_response(), submitted)
+        }
+        Err(e) => submit_error_response(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct ArtistDownload {
+    artist_id: String,
+    auth_cookie: String,
+    /// Release types to include; all if absent.
+    types: Option<Vec<zvuk::ReleaseType>>,
+    year_from: Option<i32>,
+    year_to: Option<i32>,
+    transcode: Option<transcode::Transcode>,
+    #[serde(default)]
+    proxy: Option<String>,
+    #[serde(default)]
+    include_lyrics: bool,
+    #[serde(default)]
+    normalize: bool,
+    label: Option<String>,
+    #[serde(default)]
+    explicit_policy: Option<config::ExplicitPolicy>,
+    /// `low` if absent.
+    #[serde(default)]
+    priority: Option<jobs::Priority>,
+    #[serde(default)]
+    not_before: Option<i64>,
+    /// Bandwidth of each track's download, in kilobits per second.
+    #[serde(default)]
+    max_kbps: Option<u32>,
+}
+
+impl Validate for ArtistDownload {
+    fn validate(&self) -> Result<(), String> {
+        validate::non_empty("artist_id", &self.artist_id)?;
+        validate::positive("max_kbps", self.max_kbps)
+    }
+}
+
+#[derive(Serialize)]
+struct EnqueuedTrack {
+    id: i64,
+    track_id: String,
+    release_id: String,
+    hash: String,
+}
+
+/// Enqueues a job for every track on the artist's matching releases. Tracks
+/// get a hash derived from their ID (see [`tenant::track_hash`]).
+async fn download_artist(
+    principal: Option<Extension<auth::Principal>>,
+    client: Option<Extension<auth::ClientId>>,
+    request_id: Option<Extension<RequestId>>,
+    Valid(req): Valid<ArtistDownload>,
+) -> axum::response::Response {
+    if let Err(e) = zvuk::check_proxy(req.proxy.as_deref()) {
+        return ApiError::new(StatusCode::BAD_REQUEST, e).into_response();
+    }
+    let lookup = zvuk::artist_releases(&req.artist_id, Some(&req.auth_cookie));
+    let releases = match zvuk::with_proxy(req.proxy.clone(), lookup).await {
+        Ok(releases) => releases,
+        Err(e) => {
+            return ApiError::upstream(&*e).into_response();
+        }
+    };
+
+    // (track, release it was first seen on); compilations repeat tracks.
+    let mut tracks: Vec<(&str, &str)> = Vec::new();
+    for release in &releases {
+        if let Some(types) = &req.types
+            && !release.kind.is_some_and(|kind| types.contains(&kind))
+        {
+            continue;
+        }
+        let year = release.year();
+        if req.year_from.is_some_and(|from| year.is_none_or(|y| y < from))
+            || req.year_to.is_some_and(|to| year.is_none_or(|y| y > to))
+        {
+            continue;
+        }
+        for id in release.track_ids() {
+            if !tracks.iter().any(|(seen, _)| *seen == id) {
+                tracks.push((id, &release.id));
+            }
+        }
+    }
+    if let Some(Extension(principal)) = &principal
+        && let Some((id, _)) = tracks.iter().find(|(id, _)| !principal.may_download(id))
+    {
+        return ApiError::new(StatusCode::FORBIDDEN, format!("token doesn't cover track {}", id)).into_response();
+    }
+
+    let owner = job_owner(&principal, &client, &request_id);
+    let label = req.label.clone().unwrap_or_else(|| format!("artist:{}", req.artist_id));
+    let mut enqueued = Vec::new();
+    for (track_id, release_id) in tracks {
+        let payload = DownloadZVUK {
+            id: track_id.to_string(),
+            hash: tenant::track_hash(track_id),
+            auth_cookie: req.auth_cookie.clone(),
+            transcode: req.transcode.clone(),
+            proxy: req.proxy.clone(),
+            include_lyrics: req.include_lyrics,
+            normalize: req.normalize,
+            label: Some(label.clone()),
+            explicit_policy: req.explicit_policy,
+            checksums: false,
+            priority: Some(req.priority.unwrap_or(jobs::Priority::Low)),
+            not_before: req.not_before,
+            idempotency_key: None,
+            max_kbps: req.max_kbps,
+        };
+        match jobs::queue().submit(&payload, &owner, None) {
+            Ok(submitted) => enqueued.push(EnqueuedTrack {
+                id: submitted.id(),
+                track_id: payload.id,
+                release_id: release_id.to_string(),
+                hash: payload.hash,
+            }),
+            // Keep what was already enqueued and report why the rest wasn't.
+            Err(e) if !enqueued.is_empty() => {
+                return (
+                    StatusCode::ACCEPTED,
+                    axum::Json(json!({ "jobs": enqueued, "error": e.to_string() })),
+                )
+                    .into_response();
+            }
+            Err(e) => return submit_error_response(e),
+        }
+    }
+    (StatusCode::ACCEPTED, axum::Json(json!({ "jobs": enqueued }))).into_response()
+}
+
+#[derive(Deserialize)]
+struct CollectionDownload {
+    /// Entry the playlist and manifest go into.
+    hash: String,
+    /// Exactly one of `release_id`, `playlist_id` and `track_ids`.
+    release_id: Option<String>,
+    playlist_id: Option<String>,
+    track_ids: Option<Vec<String>>,
+    /// Playlist title; the release's or playlist's own if absent.
+    title: Option<String>,
+    #[serde(default)]
+    auth_cookie: String,
+    transcode: Option<transcode::Transcode>,
+    #[serde(default)]
+    proxy: Option<String>,
+    #[serde(default)]
+    include_lyrics: bool,
+    #[serde(default)]
+    normalize: bool,
+    label: Option<String>,
+    #[serde(default)]
+    explicit_policy: Option<config::ExplicitPolicy>,
+    /// `low` if absent.
+    #[serde(default)]
+    priority: Option<jobs::Priority>,
+    #[serde(default)]
+    not_before: Option<i64>,
+    /// Bandwidth of each track's download, in kilobits per second.
+    #[serde(default)]
+    max_kbps: Option<u32>,
+}
+
+impl Validate for CollectionDownload {
+    fn validate(&self) -> Result<(), String> {
+        if let Some(id) = &self.release_id {
+            validate::non_empty("release_id", id)?;
+        }
+        if let Some(id) = &self.playlist_id {
+            validate::non_empty("playlist_id", id)?;
+        }
+        validate::ids("track_ids", self.track_ids.as_deref().unwrap_or_default())?;
+        validate::positive("max_kbps", self.max_kbps)
+    }
+}
+
+/// Enqueues a job for every track of a release, playlist or track list and
+/// writes `playlist.m3u8` and a manifest listing them into `hash`'s entry.
+/// Tracks go into their own entries (see [`tenant::track_hash`]); ones
+/// already cached aren't downloaded again.
+async fn download_collection(
+    principal: Option<Extension<auth::Principal>>,
+    client: Option<Extension<auth::ClientId>>,
+    request_id: Option<Extension<RequestId>>,
+    Valid(req): Valid<CollectionDownload>,
+) -> axum::response::Response {
+    let hash = match canonical_hash(&principal, &req.hash) {
+        Ok(hash) => hash,
+        Err(e) => return ApiError::new(StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    if let Err(e) = zvuk::check_proxy(req.proxy.as_deref()) {
+        return ApiError::new(StatusCode::BAD_REQUEST, e).into_response();
+    }
+    if let Err(e) = accounts::check_cookie(&req.auth_cookie) {
+        return ApiError::new(StatusCode::BAD_REQUEST, e).into_response();
+    }
+    let cookie = Some(req.auth_cookie.as_str()).filter(|c| !c.is_empty());
+    let lookup = match (&req.release_id, &req.playlist_id, &req.track_ids) {
+        (Some(id), None, None) => Some((collection::Kind::Release, zvuk::release_tracks(id, cookie).boxed())),
+        (None, Some(id), None) => Some((collection::Kind::Playlist, zvuk::playlist_tracks(id, cookie).boxed())),
+        (None, None, Some(_)) => None,
+        _ => {
+            let error = "give exactly one of release_id, playlist_id and track_ids";
+            return ApiError::new(StatusCode::BAD_REQUEST, error).into_response();
+        }
+    };
+    let (kind, id, title, mut ids) = match lookup {
+        Some((kind, lookup)) => match zvuk::with_proxy(req.proxy.clone(), lookup).await {
+            Ok(list) => {
+                let ids: Vec<String> = list.track_ids().map(String::from).collect();
+                (kind, list.id, Some(list.title), ids)
+            }
+            Err(e) => {
+                return ApiError::upstream(&*e).into_response();
+            }
+        },
+        None => (collection::Kind::Tracks, hash.clone(), None, req.track_ids.unwrap_or_default()),
+    };
+    let mut seen = HashSet::new();
+    ids.retain(|id| seen.insert(id.clone()));
+    if ids.is_empty() {
+        return ApiError::new(StatusCode::BAD_REQUEST, "no tracks to download").into_response();
+    }
+    if let Some(Extension(principal)) = &principal
+        && let Some(id) = ids.iter().find(|id| !principal.may_download(id))
+    {
+        return ApiError::new(StatusCode::FORBIDDEN, format!("token doesn't cover track {}", id)).into_response();
+    }
+
+    let owner = job_owner(&principal, &client, &request_id);
+    let label = req.label.clone().unwrap_or_else(|| format!("collection:{}", hash));
+    let mut tracks = Vec::new();
+    for (i, track_id) in ids.iter().enumerate() {
+        let track_hash = tenant::track_hash(track_id);
+        if entry_dir(&track_hash).join(manifest::MANIFEST_FILE).exists() {
+            tracks.push(collection::Track::new(i + 1, track_id, &track_hash, None));
+            continue;
+        }
+        let payload = DownloadZVUK {
+            id: track_id.clone(),
+            hash: track_hash,
+            auth_cookie: req.auth_cookie.clone(),
+            transcode: req.transcode.clone(),
+            proxy: req.proxy.clone(),
+            include_lyrics: req.include_lyrics,
+            normalize: req.normalize,
+            label: Some(label.clone()),
+            explicit_policy: req.explicit_policy,
+            checksums: false,
+            priority: Some(req.priority.unwrap_or(jobs::Priority::Low)),
+            not_before: req.not_before,
+            idempotency_key: None,
+            max_kbps: req.max_kbps,
+        };
+        match jobs::queue().submit(&payload, &owner, None) {
+            Ok(submitted) => tracks.push(collection::Track::new(i + 1, track_id, &payload.hash, Some(submitted.id()))),
+            // Nothing is written until every track was accepted; jobs already
+            // enqueued still download their tracks.
+            Err(e) => return submit_error_response(e),
+        }
+    }
+
+    let jobs: Vec<i64> = tracks.iter().filter_map(|t| t.job).collect();
+    let title = req.title.or(title);
+    let created = async {
+        collection::create(&hash, &id, title.as_deref(), kind, tracks.clone()).await?;
+        jobs::queue().store.link_collection(&hash, &jobs)?;
+        // Picks up tracks that were cached, or finished before being linked.
+        collection::refresh(&hash).await
+    };
+    if let Err(e) = created.await {
+        return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+    (StatusCode::ACCEPTED, axum::Json(json!({ "hash": hash, "jobs": jobs }))).into_response()
+}
+
+/// Returns an entry's `manifest.json`.
+async fn entry_manifest(
+    principal: Option<Extension<auth::Principal>>,
+    Path(hash): Path<String>,
+) -> axum::response::Response {
+    let hash = match canonical_hash(&principal, &hash) {
+        Ok(hash) => hash,
+        Err(e) => return ApiError::new(StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    match manifest::read(&entry_dir(&hash)).await {
+        Ok(manifest) => axum::Json(manifest).into_response(),
+        Err(_) => ApiError::new(StatusCode::NOT_FOUND, "no manifest for this entry").into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct DiffRequest {
+    ids: Vec<String>,
+    /// When set, tracks this session can't stream count as unavailable.
+    auth_cookie: Option<String>,
+    #[serde(default)]
+    proxy: Option<String>,
+}
+
+impl Validate for DiffRequest {
+    fn validate(&self) -> Result<(), String> {
+        validate::ids("ids", &self.ids)
+    }
+}
+
+#[derive(Serialize)]
+struct CachedTrack {
+    id: String,
+    hash: String,
+}
+
+/// Splits a list of track IDs into what's already cached, what can be
+/// downloaded, and what Zvuk doesn't offer.
+async fn diff(Valid(req): Valid<DiffRequest>) -> axum::response::Response {
+    if let Err(e) = zvuk::check_proxy(req.proxy.as_deref()) {
+        return ApiError::new(StatusCode::BAD_REQUEST, e).into_response();
+    }
+
+    let mut ids = req.ids;
+    let mut seen = HashSet::new();
+    ids.retain(|id| seen.insert(id.clone()));
+
+    let mut cached = Vec::new();
+    let mut missing = Vec::new();
+    for id in ids {
+        let hit = match aliases::store().by_track(&id) {
+            Ok(found) => found
+                .into_iter()
+                .find(|a| entry_dir(&a.hash).join(manifest::MANIFEST_FILE).exists()),
+            Err(e) => {
+                return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+            }
+        };
+        match hit {
+            Some(alias) => cached.push(CachedTrack { id, hash: alias.hash }),
+            None => missing.push(id),
+        }
+    }
+
+    let lookup = async {
+        let mut available: Vec<String> = zvuk::tracks_meta(&missing, req.auth_cookie.as_deref())
+            .await?
+            .into_iter()
+            .map(|m| m.id)
+            .collect();
+        if let Some(cookie) = &req.auth_cookie {
+            let streamable = zvuk::streamable(&available, cookie).await?;
+            available.retain(|id| streamable.contains(id));
+        }
+        Ok::<_, Box<dyn Error>>(available.into_iter().collect::<HashSet<_>>())
+    };
+    let available = match zvuk::with_proxy(req.proxy, lookup).await {
+        Ok(available) => available,
+        Err(e) => {
+            return ApiError::upstream(&*e).into_response();
+        }
+    };
+    let (downloadable, unavailable): (Vec<String>, Vec<String>) =
+        missing.into_iter().partition(|id| available.contains(id));
+    axum::Json(json!({
+        "cached": cached,
+        "downloadable": downloadable,
+        "unavailable": unavailable,
+    }))
+    .into_response()
+}
+
+#[derive(Deserialize)]
+struct EventFilter {
+    label: Option<String>,
+    tenant: Option<String>,
+}
+
+/// Streams every job state change as server-sent events. Callers that belong
+/// to a tenant only see that tenant's jobs.
+async fn job_events(
+    principal: Option<Extension<auth::Principal>>,
+    Query(mut filter): Query<EventFilter>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    if let Some(tenant) = principal.as_ref().and_then(|Extension(p)| p.tenant()) {
+        filter.tenant = Some(tenant.to_string());
+    }
+    let events = BroadcastStream::new(jobs::queue().subscribe()).filter_map(move |event| {
+        let event = match event {
+            Ok(job) => {
+                if filter.label.as_ref().is_some_and(|l| job.label.as_ref() != Some(l))
+                    || filter.tenant.as_ref().is_some_and(|t| job.tenant.as_ref() != Some(t))
+                {
+                    return None;
+                }
+                Event::default().event("job").json_data(&job).ok()?
+            }
+            // The subscriber fell behind; tell it how many events it missed.
+            Err(BroadcastStreamRecvError::Lagged(missed)) => Event::default().event("lagged").data(missed.to_string()),
+        };
+        Some(Ok(event))
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// Whether the caller may see `job`; see [`auth::Principal::may_see`].
+fn may_see(principal: &Option<Extension<auth::Principal>>, job: &jobs::Job) -> bool {
+    principal.as_ref().is_none_or(|Extension(p)| p.may_see(job))
+}
+
+async fn list_jobs(
+    principal: Option<Extension<auth::Principal>>,
+    Query(mut filter): Query<jobs::JobFilter>,
+) -> axum::response::Response {
+    if let Some(tenant) = principal.as_ref().and_then(|Extension(p)| p.tenant()) {
+        filter.tenant = Some(tenant.to_string());
+    }
+    if let Some(hash) = &filter.hash {
+        filter.hash = match canonical_hash(&principal, hash) {
+            Ok(hash) => Some(hash),
+            Err(e) => {
+                return ApiError::new(StatusCode::BAD_REQUEST, e).into_response();
+            }
+        };
+    }
+    match jobs::queue().store.list(&filter) {
+        Ok(list) => axum::Json(list).into_response(),
+        Err(e) => ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct GetJobParams {
+    /// Seconds to wait for the job to settle before answering.
+    wait: Option<u64>,
+}
+
+/// Longest `wait` honoured; kept under the metadata route timeout.
+fn max_job_wait() -> Duration {
+    let routes = &config::get().routes;
+    let timeout = routes.policy(&routes.metadata, "/jobs/{id}").timeout_secs.saturating_sub(1);
+    Duration::from_secs(timeout.min(60))
+}
+
+/// Queue depth per priority, plus how many jobs are running.
+async fn queue_depth() -> axum::response::Response {
+    let queue = jobs::queue();
+    axum::Json(json!({
+        "running": queue.running_count(),
+        "queued": queue.depth(),
+        "next_scheduled": queue.next_scheduled(),
+        "paused": queue.paused(),
+    }))
+    .into_response()
+}
+
+/// Returns a job; with `?wait=N`, long-polls until it's done, failed,
+/// cancelled or awaiting credentials, or `N` seconds have passed. Tenant keys
+/// only see their tenant's jobs.
+async fn get_job(
+    principal: Option<Extension<auth::Principal>>,
+    Path(id): Path<i64>,
+    Query(params): Query<GetJobParams>,
+) -> axum::response::Response {
+    match jobs::queue().store.get(id) {
+        Ok(Some(job)) if may_see(&principal, &job) => {}
+        Ok(_) => return ApiError::new(StatusCode::NOT_FOUND, "no such job").into_response(),
+        Err(e) => return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+    let job = match params.wait {
+        Some(wait) => jobs::queue().wait_settled(id, Duration::from_secs(wait).min(max_job_wait())).await,
+        None => jobs::queue().store.get(id),
+    };
+    match job {
+        Ok(Some(job)) => axum::Json(job).into_response(),
+        Ok(None) => ApiError::new(StatusCode::NOT_FOUND, "no such job").into_response(),
+        Err(e) => ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// Cancels a queued, running or parked job. Tenant keys can only cancel
+/// their tenant's jobs.
+async fn cancel_job(
+    principal: Option<Extension<auth::Principal>>,
+    Path(id): Path<i64>,
+) -> axum::response::Response {
+    let queue = jobs::queue();
+    if let Some(tenant) = principal.as_ref().and_then(|Extension(p)| p.tenant())
+        && !matches!(queue.store.get(id), Ok(Some(job)) if job.tenant.as_deref() == Some(tenant))
+    {
+        return ApiError::new(StatusCode::NOT_FOUND, "no such job").into_response();
+    }
+    match queue.cancel(id) {
+        Ok(was) => axum::Json(json!({ "ok": true, "id": id, "was": was })).into_response(),
+        Err(e) => {
+            let status = match e {
+                jobs::CancelError::NotFound => StatusCode::NOT_FOUND,
+                jobs::CancelError::Finished(_) => StatusCode::CONFLICT,
+                jobs::CancelError::Store(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            ApiError::new(status, e.to_string()).into_response()
+        }
+    }
+}
+
+/// The job with every attempt's error chain and suggestions for the
+/// failure categories seen, most recent first.
+async fn explain_job(
+    principal: Option<Extension<auth::Principal>>,
+    Path(id): Path<i64>,
+) -> axum::response::Response {
+    let store = &jobs::queue().store;
+    let found = store
+        .get(id)
+        .and_then(|job| job.map(|job| Ok((job, store.attempts(id)?))).transpose());
+    match found {
+        Ok(Some((job, attempts))) if may_see(&principal, &job) => {
+            let mut hints = Vec::new();
+            for category in attempts.iter().rev().filter_map(|a| a.category) {
+                let hint = serde_json::json!({ "category": category, "hint": category.hint() });
+                if !hints.contains(&hint) {
+                    hints.push(hint);
+                }
+            }
+            axum::Json(serde_json::json!({ "job": job, "attempts": attempts, "hints": hints })).into_response()
+        }
+        Ok(_) => ApiError::new(StatusCode::NOT_FOUND, "no such job").into_response(),
+        Err(e) => ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DownloadZVUK {
+    pub id: String,
+    pub hash: String,
+    /// Zvuk session cookie; an account from `[accounts.pool]` is used if
+    /// empty or absent.
+    #[serde(default)]
+    pub auth_cookie: String,
+    pub transcode: Option<transcode::Transcode>,
+    /// Named proxy from `[proxies]`; `TRI_ZVUK_PROXY` (or none) if absent.
+    #[serde(default)]
+    pub proxy: Option<String>,
+    /// Also store lyrics (`lyrics.lrc` if synced, `lyrics.txt` otherwise).
+    #[serde(default)]
+    pub include_lyrics: bool,
+    /// Measure loudness into `loudness.json` and add ReplayGain tags.
+    #[serde(default)]
+    pub normalize: bool,
+    /// Free-form tag for grouping jobs (e.g. a sync run); filterable in
+    /// `/jobs` and `/events`.
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Overrides `explicit_policy` from the config.
+    #[serde(default)]
+    pub explicit_policy: Option<config::ExplicitPolicy>,
+    /// Return the size and SHA-256 of every file in the entry from `/dl`.
+    #[serde(default)]
+    pub checksums: bool,
+    /// Queue priority; see [`jobs::Priority`] for the defaults.
+    #[serde(default)]
+    pub priority: Option<jobs::Priority>,
+    /// Unix timestamp before which the job isn't started (`/jobs` only).
+    #[serde(default)]
+    pub not_before: Option<i64>,
+    /// Same as the `Idempotency-Key` header, which wins if both are given.
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// Bandwidth of this download in kilobits per second, on top of
+    /// `[download] max_kbps`.
+    #[serde(default)]
+    pub max_kbps: Option<u32>,
+}
+
+impl Validate for DownloadZVUK {
+    fn validate(&self) -> Result<(), String> {
+        validate::non_empty("id", &self.id)?;
+        validate::positive("max_kbps", self.max_kbps)
+    }
+}
+
+#[derive(Serialize)]
+struct IsOK {
+    ok: bool,
+    error: String,
+}
+
+/// Runs the HTTP service until SIGTERM/SIGINT.
+pub async fn serve() {
+    tracing_subscriber::fmt::init();
+    config::init();
+    manifest::init();
+    auth::init();
+    zvuk::init();
+    let jobs_config = &config::get().jobs;
+
+    tokio::fs::create_dir_all(&*CACHEDIR).await.unwrap();
+    let db = jobs_config.db_path.clone().unwrap_or_else(|| CACHEDIR.join("jobs.sqlite3"));
+    aliases::open(&db).expect("couldn't open alias table");
+    jobs::start(&db, jobs_config.concurrency).expect("couldn't open job database");
+    tokio::spawn(gc::run_periodically());
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup());
+
+    let clients = limits::ClientLimiter::new();
+    // Shared with gRPC, whose `Download` counts against the same limits.
+    let download_limiter = limits::GroupLimiter::new(|r| &r.download);
+    // Body limits vary per route and follow reloads, so `limits::enforce`
+    // applies them instead of the extractors' default.
+    let metadata = Router::new()
+        .route("/manifest/key", get(signing_key))
+        .route("/search", get(search))
+        .route("/jobs", get(list_jobs))
+        .route("/jobs/queue", get(queue_depth))
+        .route("/jobs/{id}", get(get_job))
+        .route("/jobs/{id}/explain", get(explain_job))
+        .route("/events", get(job_events))
+        .route("/diff", post(diff))
+        .route("/lyrics/{id}", get(lyrics))
+        .route("/art/{hash}/{size}", get(cover_art))
+        .route("/audio/{hash}/{quality}", get(audio))
+        .route("/cache/{hash}/compare", get(compare_entry))
+        .route("/cache/{hash}/manifest", get(entry_manifest))
+        .route("/resolve/hash/{hash}", get(resolve_hash))
+        .route("/resolve/track/{id}", get(resolve_track))
+        .route("/resolve/isrc/{isrc}", get(resolve_isrc))
+        .route("/lookup/{id}", get(lookup_track))
+        .layer(DefaultBodyLimit::disable())
+        .route_layer(from_fn_with_state(limits::GroupLimiter::new(|r| &r.metadata), limits::enforce))
+        .route_layer(from_fn_with_state(clients.clone(), limits::per_client))
+        .route_layer(from_fn_with_state(auth::Role::ReadOnly, auth::require));
+    let download = Router::new()
+        .route("/dl", post(download))
+        .route("/dl/artist", post(download_artist))
+        .route("/dl/collection", post(download_collection))
+        .route("/cache/{hash}/verify", post(verify_entry))
+        .route("/jobs", post(submit_job))
+        .route("/jobs/{id}", delete(cancel_job))
+        .route("/stream/{id}", get(stream_track))
+        .route("/auth/validate", post(validate_session))
+        .layer(DefaultBodyLimit::disable())
+        .route_layer(from_fn_with_state(download_limiter.clone(), limits::enforce))
+        .route_layer(from_fn_with_state(clients.clone(), limits::per_client))
+        .route_layer(from_fn_with_state(auth::Role::Submit, auth::require));
+    let admin = Router::new()
+        .route("/cache/{hash}", delete(purge))
+        .route("/auth/token", post(mint_token))
+        .route("/admin/hydrate", post(start_hydration).get(hydration_status))
+        .route("/admin/diagnose", post(diagnose))
+        .route("/admin/gc", post(collect_garbage))
+        .route("/config/reload", post(reload_config))
+        .route("/accounts", get(account_pool))
+        .route("/stats", get(stats))
+        .layer(DefaultBodyLimit::disable())
+        .route_layer(from_fn_with_state(limits::GroupLimiter::new(|r| &r.admin), limits::enforce))
+        .route_layer(from_fn_with_state(clients.clone(), limits::per_client))
+        .route_layer(from_fn_with_state(auth::Role::Admin, auth::require));
+
+    // Probes for the orchestrator; never behind auth or limits.
+    let probes = Router::new()
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz));
+
+    // Outermost first: assign the ID, open the span, echo the ID back.
+    let app = Router::new()
+        .merge(probes)
+        .merge(metadata)
+        .merge(download)
+        .merge(admin)
+        .layer(PropagateRequestIdLayer::new(REQUEST_ID))
+        .layer(TraceLayer::new_for_http().make_span_with(request_span))
+        .layer(SetRequestIdLayer::new(REQUEST_ID, MakeRequestUuid));
+
+    // Every listener stops on the same signal, after the jobs have drained.
+    let shutdown = async {
+        shutdown_signal().await;
+        let grace = Duration::from_secs(config::get().jobs.shutdown_grace_secs);
+        jobs::queue().shutdown(grace).await;
+    }
+    .shared();
+    let tcp = async {
+        if !config::get().tcp {
+            return Ok(());
+        }
+        let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", config::get().port)).await?;
+        let Some(tls) = &config::get().tls else {
+            return axum::serve(listener, app.clone().into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(shutdown.clone())
+                .await;
+        };
+        let listener = tls::listen(listener, tls, b"http/1.1").map_err(std::io::Error::other)?;
+        tracing::info!("serving HTTPS on port {}", config::get().port);
+        // `tap_io` is what gives a custom listener `ConnectInfo<SocketAddr>`.
+        axum::serve(listener.tap_io(|_| {}), app.clone().into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(shutdown.clone())
+            .await
+    };
+    let unix = async {
+        #[cfg(unix)]
+        if let Some(path) = &config::get().socket {
+            let (listener, _cleanup) = socket::bind(path, config::get().socket_mode).await?;
+            tracing::info!("listening on {}", path.display());
+            // No peer address, so clients without a key share one rate limit.
+            axum::serve(listener, app.clone().into_make_service())
+                .with_graceful_shutdown(shutdown.clone())
+                .await?;
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    let grpc = async {
+        match config::get().grpc_port {
+            Some(port) => grpc::serve(port, clients.clone(), download_limiter.clone(), shutdown.clone()).await,
+            None => Ok(()),
+        }
+    };
+    tokio::try_join!(tcp, unix, grpc).unwrap();
+}
+
+/// SIGHUP re-reads the config, like `POST /config/reload`.
+#[cfg(unix)]
+async fn reload_on_hangup() {
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("couldn't listen for SIGHUP");
+    while hangup.recv().await.is_some() {
+        match config::reload() {
+            Ok(restart_required) if restart_required.is_empty() => tracing::info!("config reloaded"),
+            Ok(restart_required) => {
+                tracing::warn!(?restart_required, "config reloaded; some changes need a restart")
+            }
+            Err(e) => tracing::error!("couldn't reload config: {}", e),
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("couldn't listen for SIGINT");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("couldn't listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(dir: &std::path::Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|file| file.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn legacy_entry_survives_a_failed_download() {
+        let cache = tempfile::tempdir().unwrap();
+        let entry = cache.path().join("hash").join("zvuk");
+        std::fs::create_dir_all(&entry).unwrap();
+        // Written before entries had manifests.
+        std::fs::write(entry.join("best.flac"), b"old").unwrap();
+        std::fs::write(entry.join("mid.mp3"), b"old").unwrap();
+
+        let before = EntrySnapshot::take(&entry).await.unwrap();
+        std::fs::write(entry.join("best.flac.part"), b"half").unwrap();
+        std::fs::write(entry.join("cover_600.jpg"), b"new").unwrap();
+        cleanup_incomplete(&entry, &before).await.unwrap();
+
+        assert_eq!(names(&entry), ["best.flac", "mid.mp3"]);
+    }
+
+    #[tokio::test]
+    async fn entry_created_by_a_failed_download_is_removed() {
+        let cache = tempfile::tempdir().unwrap();
+        let entry = cache.path().join("hash").join("zvuk");
+
+        let before = EntrySnapshot::take(&entry).await.unwrap();
+        std::fs::create_dir_all(&entry).unwrap();
+        std::fs::write(entry.join("best.flac"), b"new").unwrap();
+        std::fs::write(entry.join("mid.mp3.part"), b"half").unwrap();
+        cleanup_incomplete(&entry, &before).await.unwrap();
+
+        assert!(names(cache.path()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn other_sources_keep_the_hash_directory() {
+        let cache = tempfile::tempdir().unwrap();
+        let entry = cache.path().join("hash").join("zvuk");
+        std::fs::create_dir_all(cache.path().join("hash").join("other")).unwrap();
+
+        let before = EntrySnapshot::take(&entry).await.unwrap();
+        std::fs::create_dir_all(&entry).unwrap();
+        std::fs::write(entry.join("best.flac.part"), b"half").unwrap();
+        cleanup_incomplete(&entry, &before).await.unwrap();
+
+        assert_eq!(names(&cache.path().join("hash")), ["other"]);
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http_body_util::Limited;
+use hyper::StatusCode;
+use tokio::time::timeout;
+
+use crate::{
+    auth::ClientId,
+    config::{self, RoutePolicy, Routes},
+    error::ApiError,
+};
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Fixed one-minute window counter. The limit is passed on each hit so it
+/// follows config reloads.
+pub struct Window {
+    started: Instant,
+    count: u32,
+}
+
+impl Window {
+    pub fn new() -> Self {
+        Window { started: Instant::now(), count: 0 }
+    }
+
+    fn expired(&self, now: Instant) -> bool {
+        now.duration_since(self.started) >= WINDOW
+    }
+
+    /// Counts a request, or returns how long until the window resets.
+    pub fn hit(&mut self, limit: u32) -> Result<(), Duration> {
+        let now = Instant::now();
+        if self.expired(now) {
+            self.started = now;
+            self.count = 0;
+        }
+        if self.count >= limit {
+            return Err(WINDOW - now.duration_since(self.started));
+        }
+        self.count += 1;
+        Ok(())
+    }
+}
+
+pub struct GroupLimiter {
+    /// Picks the group's policy out of the current config.
+    policy: fn(&Routes) -> &RoutePolicy,
+    window: Mutex<Window>,
+}
+
+impl GroupLimiter {
+    pub fn new(policy: fn(&Routes) -> &RoutePolicy) -> Arc<Self> {
+        Arc::new(GroupLimiter { policy, window: Mutex::new(Window::new()) })
+    }
+
+    /// Counts a request against the group's `rate_per_minute`, or returns
+    /// how long until the window resets.
+    pub fn check(&self) -> Result<(), Duration> {
+        match (self.policy)(&config::get().routes).rate_per_minute {
+            Some(limit) => self.window.lock().unwrap().hit(limit),
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn too_many_requests(retry_after: Duration, error: &str) -> Response {
+    ApiError::new(StatusCode::TOO_MANY_REQUESTS, error).retry_after(retry_after).into_response()
+}
+
+/// Request rate per [`ClientId`], shared by every route group.
+#[derive(Default)]
+pub struct ClientLimiter {
+    windows: Mutex<HashMap<ClientId, Window>>,
+}
+
+impl ClientLimiter {
+    pub fn new() -> Arc<Self> {
+        Arc::new(ClientLimiter::default())
+    }
+
+    /// Counts a request of `client` against `[clients] rate_per_minute`, or
+    /// returns how long until it may send another.
+    pub fn check(&self, client: &ClientId) -> Result<(), Duration> {
+        match config::get().clients.rate_per_minute {
+            Some(limit) => self.hit(client, limit),
+            None => Ok(()),
+        }
+    }
+
+    fn hit(&self, client: &ClientId, limit: u32) -> Result<(), Duration> {
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() > 10_000 {
+            let now = Instant::now();
+            windows.retain(|_, w| !w.expired(now));
+        }
+        windows
+            .entry(client.clone())
+            .or_insert_with(Window::new)
+            .hit(limit)
+    }
+}
+
+/// Falls back to the peer IP when auth didn't identify the client, then
+/// applies the per-client request rate.
+pub async fn per_client(State(limiter): State<Arc<ClientLimiter>>, mut req: Request, next: Next) -> Response {
+    if req.extensions().get::<ClientId>().is_none() {
+        let ip = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        req.extensions_mut().insert(ClientId(format!("ip:{}", ip)));
+    }
+    if let Err(retry_after) = limiter.check(req.extensions().get::<ClientId>().unwrap()) {
+        return too_many_requests(retry_after, "client rate limit exceeded");
+    }
+    next.run(req).await
+}
+
+/// Applies the group's rate limit, then the route's body limit and timeout
+/// (the group's unless `[routes.paths]` overrides them).
+pub async fn enforce(State(limiter): State<Arc<GroupLimiter>>, req: Request, next: Next) -> Response {
+    if let Err(retry_after) = limiter.check() {
+        return too_many_requests(retry_after, "rate limit exceeded");
+    }
+    let routes = &config::get().routes;
+    let group = (limiter.policy)(routes);
+    let path = req.extensions().get::<MatchedPath>().map(|p| p.as_str()).unwrap_or_default();
+    let policy = routes.policy(group, path);
+    // Extractors turn the limit error into a 413.
+    let mut req = req.map(|body| Body::new(Limited::new(body, policy.body_limit)));
+    let deadline = Deadline::default();
+    req.extensions_mut().insert(deadline.clone());
+    match run_until(policy.timeout(), &deadline, next.run(req)).await {
+        Some(res) => res,
+        None => ApiError::new(StatusCode::GATEWAY_TIMEOUT, "request timed out").into_response(),
+    }
+}
+
+/// Tells a handler's drop code whether it's being dropped because the
+/// route's timeout ran out rather than because the client went away.
+#[derive(Clone, Default)]
+pub struct Deadline(Arc<AtomicBool>);
+
+impl Deadline {
+    pub fn passed(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn pass(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Runs `f` for at most `limit`. If it runs out, `deadline` is marked as
+/// passed before `f` is dropped.
+async fn run_until<F: Future>(limit: Duration, deadline: &Deadline, f: F) -> Option<F::Output> {
+    tokio::pin!(f);
+    match timeout(limit, &mut f).await {
+        Ok(output) => Some(output),
+        Err(_) => {
+            deadline.pass();
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records whether the deadline had passed when it was dropped.
+    struct Probe(Deadline, Arc<AtomicBool>);
+
+    impl Drop for Probe {
+        fn drop(&mut self) {
+            self.1.store(self.0.passed(), Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deadline_passes_before_a_timed_out_handler_is_dropped() {
+        let (deadline, passed) = (Deadline::default(), Arc::new(AtomicBool::new(false)));
+        let probe = Probe(deadline.clone(), passed.clone());
+        let handler = async move {
+            let _probe = probe;
+            std::future::pending::<()>().await
+        };
+        assert!(run_until(Duration::from_secs(5), &deadline, handler).await.is_none());
+        assert!(passed.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dropped_request_leaves_the_deadline_unpassed() {
+        let (deadline, passed) = (Deadline::default(), Arc::new(AtomicBool::new(true)));
+        let probe = Probe(deadline.clone(), passed.clone());
+        let handler = async move {
+            let _probe = probe;
+            std::future::pending::<()>().await
+        };
+        // The client hanging up drops the whole request future early.
+        let request = run_until(Duration::from_secs(5), &deadline, handler);
+        assert!(tokio::time::timeout(Duration::from_secs(1), request).await.is_err());
+        assert!(!passed.load(Ordering::SeqCst));
+        assert!(!deadline.passed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handler_within_the_limit_returns() {
+        let deadline = Deadline::default();
+        assert_eq!(run_until(Duration::from_secs(5), &deadline, async { 1 }).await, Some(1));
+        assert!(!deadline.passed());
+    }
+}
@@ -0,0 +1,106 @@
+use std::{error::Error, path::{Path, PathBuf}};
+
+use id3::{frame::ExtendedText, Tag, TagLike, Version};
+use serde::Serialize;
+use tokio::process::Command;
+
+use crate::{dedupe, metadata, transcode::FFMPEG};
+
+pub const LOUDNESS_FILE: &str = "loudness.json";
+/// ReplayGain 2.0 reference level.
+pub const REFERENCE_LUFS: f64 = -18.0;
+
+/// EBU R128 measurements of one file and the ReplayGain derived from them.
+#[derive(Serialize)]
+pub struct Loudness {
+    pub name: String,
+    pub integrated_lufs: f64,
+    pub range_lu: f64,
+    pub true_peak_dbfs: f64,
+    /// Gain that brings the track to [`REFERENCE_LUFS`].
+    pub track_gain_db: f64,
+    /// True peak as a linear amplitude (1.0 is full scale).
+    pub track_peak: f64,
+}
+
+#[derive(Serialize)]
+struct Sidecar<'a> {
+    reference_lufs: f64,
+    files: &'a [Loudness],
+}
+
+/// Measures `path` with ffmpeg's `ebur128` filter.
+pub async fn analyze(path: &Path) -> Result<Loudness, Box<dyn Error>> {
+    let output = Command::new(FFMPEG.as_str())
+        .args(["-hide_banner", "-nostats", "-i"])
+        .arg(path)
+        .args(["-vn", "-af", "ebur128=peak=true", "-f", "null", "-"])
+        .output()
+        .await
+        .map_err(|e| format!("couldn't run {}: {}", FFMPEG.as_str(), e))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        return Err(format!("ffmpeg exited with {}: {}", output.status, stderr.trim()).into());
+    }
+
+    // The totals follow the last "Summary:" line, one "<label>: <value> <unit>"
+    // per line; the per-frame log before it uses the same labels.
+    let summary = stderr.rsplit_once("Summary:").map(|(_, s)| s).unwrap_or_default();
+    let value = |label: &str| {
+        summary
+            .lines()
+            .find_map(|line| line.trim().strip_prefix(label))
+            .and_then(|rest| rest.split_whitespace().next()?.parse::<f64>().ok())
+            .ok_or_else(|| format!("ffmpeg reported no {} for {}", label.trim_end_matches(':'), path.display()))
+    };
+    let integrated_lufs = value("I:")?;
+    let range_lu = value("LRA:")?;
+    let true_peak_dbfs = value("Peak:")?;
+    Ok(Loudness {
+        name: path.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_string(),
+        integrated_lufs,
+        range_lu,
+        true_peak_dbfs,
+        track_gain_db: REFERENCE_LUFS - integrated_lufs,
+        track_peak: 10f64.powf(true_peak_dbfs / 20.0),
+    })
+}
+
+fn tag_mp3(path: &Path, loudness: &Loudness) -> Result<(), id3::Error> {
+    let mut tag = Tag::read_from_path(path).unwrap_or_else(|_| Tag::new());
+    for (description, value) in [
+        ("REPLAYGAIN_TRACK_GAIN", format!("{:+.2} dB", loudness.track_gain_db)),
+        ("REPLAYGAIN_TRACK_PEAK", format!("{:.6}", loudness.track_peak)),
+        ("REPLAYGAIN_REFERENCE_LOUDNESS", format!("{:.1} LUFS", REFERENCE_LUFS)),
+    ] {
+        tag.remove_extended_text(Some(description), None);
+        tag.add_frame(ExtendedText { description: description.to_string(), value });
+    }
+    tag.write_to_path(path, Version::Id3v24)
+}
+
+/// Measures every file in `audio`, writes `loudness.json` into the entry and
+/// adds ReplayGain tags to the MP3s. Other formats only get the sidecar.
+#[tracing::instrument(name = "analyze_loudness", skip_all)]
+pub async fn write(entry: &Path, audio: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+    let mut files = Vec::new();
+    for path in audio {
+        files.push(analyze(path).await?);
+    }
+    let sidecar = Sidecar { reference_lufs: REFERENCE_LUFS, files: &files };
+    tokio::fs::write(entry.join(LOUDNESS_FILE), serde_json::to_vec_pretty(&sidecar)?).await?;
+
+    let tagged: Vec<(PathBuf, Loudness)> =
+        audio.iter().cloned().zip(files).filter(|(p, _)| metadata::is_mp3(p)).collect();
+    for (path, _) in &tagged {
+        dedupe::unshare(path).await?;
+    }
+    tokio::task::spawn_blocking(move || {
+        for (path, loudness) in &tagged {
+            tag_mp3(path, loudness).map_err(|e| format!("couldn't tag {}: {}", path.display(), e))?;
+        }
+        Ok::<(), String>(())
+    })
+    .await??;
+    Ok(())
+}
@@ -0,0 +1,347 @@
+use std::{env, error::Error, path::{Path, PathBuf}};
+
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use once_cell::sync::Lazy;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use crate::{collection::Collection, config::ExplicitPolicy, metadata, source, zvuk};
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+pub const CHECKSUMS_FILE: &str = "SHA256SUMS";
+pub const SIGNATURE_EXT: &str = "sig";
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Manifest {
+    /// `[cache] source` of the service that wrote the entry.
+    #[serde(default = "default_source")]
+    pub source: String,
+    pub id: String,
+    pub hash: String,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub artist: Option<String>,
+    /// Track length in seconds.
+    #[serde(default)]
+    pub duration: Option<u64>,
+    #[serde(default)]
+    pub explicit: Option<bool>,
+    /// How the explicit policy picked this track; absent for `as_requested`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<Variant>,
+    pub files: Vec<FileRecord>,
+    /// Tracks of a release or playlist entry; `files` then holds only the
+    /// playlist.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collection: Option<Collection>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Variant {
+    pub policy: ExplicitPolicy,
+    /// Track ID the download was requested for.
+    pub requested_id: String,
+    /// The other version of the track, if Zvuk has one.
+    pub alternate_id: Option<String>,
+    /// Entry holding the other version (`both` only).
+    pub alternate_hash: Option<String>,
+}
+
+fn default_source() -> String {
+    "zvuk".to_string()
+}
+
+impl Manifest {
+    pub fn new(id: &str, hash: &str, files: Vec<FileRecord>, meta: Option<&zvuk::TrackMeta>) -> Self {
+        Manifest {
+            source: source().to_string(),
+            id: id.to_string(),
+            hash: hash.to_string(),
+            title: meta.map(|m| m.title.clone()),
+            artist: meta.and_then(metadata::artist_names),
+            duration: meta.and_then(|m| m.duration),
+            explicit: meta.and_then(|m| m.explicit),
+            variant: None,
+            files,
+            collection: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FileRecord {
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+static SIGNING_KEY: Lazy<Option<SigningKey>> = Lazy::new(|| {
+    let path = env::var("TRI_ZVUK_SIGNING_KEY").ok().map(PathBuf::from)?;
+    match load_or_create_key(&path) {
+        Ok(key) => Some(key),
+        Err(e) => panic!("couldn't load signing key {}: {}", path.display(), e),
+    }
+});
+
+fn load_or_create_key(path: &Path) -> Result<SigningKey, Box<dyn Error>> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let key = SigningKey::generate(&mut OsRng);
+    // The key is written in full under a temporary name and then linked into
+    // place, which fails if the file exists. So of two processes starting at
+    // once, one installs its key and the other reads that complete key.
+    let tmp = path.with_extension(format!("{}.{:x}.tmp", std::process::id(), OsRng.next_u64()));
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let written = options.open(&tmp).and_then(|mut file| {
+        std::io::Write::write_all(&mut file, hex::encode(key.to_bytes()).as_bytes())?;
+        file.sync_all()
+    });
+    let linked = written.and_then(|()| std::fs::hard_link(&tmp, path));
+    let _ = std::fs::remove_file(&tmp);
+    match linked {
+        Ok(()) => Ok(key),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => read_key(path),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn read_key(path: &Path) -> Result<SigningKey, Box<dyn Error>> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            tracing::warn!(
+                "signing key {} is readable by other users (mode {:o}); run chmod 600 on it",
+                path.display(),
+                mode & 0o777
+            );
+        }
+    }
+    let seed = hex::decode(std::fs::read_to_string(path)?.trim())?;
+    let seed: [u8; 32] = seed
+        .try_into()
+        .map_err(|_| "signing key must be a 32-byte hex seed")?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+pub fn init() {
+    Lazy::force(&SIGNING_KEY);
+}
+
+/// Held by anything that rewrites an entry outside a job (stream copies,
+/// hydration, extension fixes), and by a job while it claims its entry, so
+/// the two never interleave.
+static ENTRIES: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Locks entries against concurrent updates. Jobs wait for it to start, so
+/// don't hold it across more than one entry.
+pub async fn lock_entries() -> tokio::sync::MutexGuard<'static, ()> {
+    ENTRIES.lock().await
+}
+
+pub fn verifying_key() -> Option<VerifyingKey> {
+    SIGNING_KEY.as_ref().map(|k| k.verifying_key())
+}
+
+/// Size and checksum of a file, hashed in chunks so large FLACs aren't held
+/// in memory.
+pub async fn file_record(path: &Path) -> std::io::Result<FileRecord> {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid file name"))?
+        .to_string();
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut size = 0u64;
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+    Ok(FileRecord { name, size, sha256: hex::encode(hasher.finalize()) })
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum FileStatus {
+    Ok,
+    Missing,
+    SizeMismatch,
+    ChecksumMismatch,
+}
+
+#[derive(Serialize)]
+pub struct FileCheck {
+    pub name: String,
+    pub status: FileStatus,
+}
+
+pub async fn read(dir: &Path) -> Result<Manifest, Box<dyn Error>> {
+    Ok(serde_json::from_slice(&tokio::fs::read(dir.join(MANIFEST_FILE)).await?)?)
+}
+
+/// Re-checks every file listed in the entry's manifest against its recorded
+/// size and checksum.
+pub async fn verify(dir: &Path, manifest: &Manifest) -> Result<Vec<FileCheck>, Box<dyn Error>> {
+    let mut checks = Vec::new();
+    for expected in &manifest.files {
+        let path = dir.join(&expected.name);
+        let status = if !tokio::fs::try_exists(&path).await? {
+            FileStatus::Missing
+        } else {
+            let actual = file_record(&path).await?;
+            if actual.size != expected.size {
+                FileStatus::SizeMismatch
+            } else if actual.sha256 != expected.sha256 {
+                FileStatus::ChecksumMismatch
+            } else {
+                FileStatus::Ok
+            }
+        };
+        checks.push(FileCheck { name: expected.name.clone(), status });
+    }
+    Ok(checks)
+}
+
+/// Writes `manifest.json` and `SHA256SUMS` into the entry directory and, when
+/// `TRI_ZVUK_SIGNING_KEY` is configured, a detached ed25519 signature for each.
+#[tracing::instrument(name = "write_manifest", skip(manifest))]
+pub async fn export(dir: &Path, manifest: &Manifest) -> Result<(), Box<dyn Error>> {
+    write_signed(dir, manifest, SIGNING_KEY.as_ref()).await
+}
+
+async fn write_signed(dir: &Path, manifest: &Manifest, key: Option<&SigningKey>) -> Result<(), Box<dyn Error>> {
+    let manifest_bytes = serde_json::to_vec_pretty(manifest)?;
+    let checksums: String = manifest
+        .files
+        .iter()
+        .map(|f| format!("{}  {}\n", f.sha256, f.name))
+        .collect();
+
+    for (name, bytes) in [
+        (MANIFEST_FILE, manifest_bytes.as_slice()),
+        (CHECKSUMS_FILE, checksums.as_bytes()),
+    ] {
+        tokio::fs::write(dir.join(name), bytes).await?;
+        if let Some(key) = key {
+            let sig = key.sign(bytes);
+            tokio::fs::write(
+                dir.join(format!("{}.{}", name, SIGNATURE_EXT)),
+                hex::encode(sig.to_bytes()),
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Signature, Verifier};
+
+    use super::*;
+
+    fn manifest(files: Vec<FileRecord>) -> Manifest {
+        Manifest {
+            source: default_source(),
+            id: "123".to_string(),
+            hash: "abc".to_string(),
+            title: None,
+            artist: None,
+            duration: None,
+            explicit: None,
+            variant: None,
+            files,
+            collection: None,
+        }
+    }
+
+    fn signature(dir: &Path, name: &str) -> Signature {
+        let hex = std::fs::read_to_string(dir.join(format!("{}.{}", name, SIGNATURE_EXT))).unwrap();
+        Signature::from_slice(&hex::decode(hex).unwrap()).unwrap()
+    }
+
+    async fn signed_entry(key: &SigningKey) -> (tempfile::TempDir, Manifest) {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("best.flac"), b"audio").unwrap();
+        let record = file_record(&dir.path().join("best.flac")).await.unwrap();
+        let manifest = manifest(vec![record]);
+        write_signed(dir.path(), &manifest, Some(key)).await.unwrap();
+        (dir, manifest)
+    }
+
+    #[tokio::test]
+    async fn signatures_verify_with_the_public_key() {
+        let key = SigningKey::generate(&mut OsRng);
+        let (dir, manifest) = signed_entry(&key).await;
+
+        for name in [MANIFEST_FILE, CHECKSUMS_FILE] {
+            let bytes = std::fs::read(dir.path().join(name)).unwrap();
+            key.verifying_key().verify(&bytes, &signature(dir.path(), name)).unwrap();
+        }
+        let checks = verify(dir.path(), &read(dir.path()).await.unwrap()).await.unwrap();
+        assert!(checks.iter().all(|c| c.status == FileStatus::Ok));
+        assert_eq!(checks.len(), manifest.files.len());
+    }
+
+    #[tokio::test]
+    async fn tampering_is_detected() {
+        let key = SigningKey::generate(&mut OsRng);
+        let (dir, manifest) = signed_entry(&key).await;
+
+        let mut tampered = manifest.clone();
+        tampered.files[0].sha256 = hex::encode(Sha256::digest(b"other"));
+        let bytes = serde_json::to_vec_pretty(&tampered).unwrap();
+        let sig = signature(dir.path(), MANIFEST_FILE);
+        assert!(key.verifying_key().verify(&bytes, &sig).is_err());
+
+        std::fs::write(dir.path().join("best.flac"), b"AUDIO").unwrap();
+        let checks = verify(dir.path(), &manifest).await.unwrap();
+        assert_eq!(checks[0].status, FileStatus::ChecksumMismatch);
+        std::fs::write(dir.path().join("best.flac"), b"audio!").unwrap();
+        let checks = verify(dir.path(), &manifest).await.unwrap();
+        assert_eq!(checks[0].status, FileStatus::SizeMismatch);
+    }
+
+    #[test]
+    fn generated_key_is_private_and_reloads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys").join("signing.key");
+        let key = load_or_create_key(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert_eq!(load_or_create_key(&path).unwrap().to_bytes(), key.to_bytes());
+    }
+
+    #[test]
+    fn racing_starts_agree_on_one_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("signing.key");
+        let keys: Vec<[u8; 32]> = std::thread::scope(|scope| {
+            let racers: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| load_or_create_key(&path).unwrap().to_bytes()))
+                .collect();
+            racers.into_iter().map(|racer| racer.join().unwrap()).collect()
+        });
+        assert!(keys.iter().all(|key| *key == keys[0]));
+        let files: Vec<_> = std::fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(files, ["signing.key"]);
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use id3::{
+    frame::{Picture, PictureType},
+    Tag, TagLike, Version,
+};
+use serde::Serialize;
+
+use crate::{aliases, config, dedupe, entry_dir, jobs, manifest, source, zvuk, CACHEDIR, PART_EXT};
+
+pub const META_FILE: &str = "meta.json";
+pub const COVER_FILE: &str = "cover.jpg";
+pub const LYRICS_SYNCED_FILE: &str = "lyrics.lrc";
+pub const LYRICS_PLAIN_FILE: &str = "lyrics.txt";
+const COVER_SIZE: &str = "600";
+
+pub fn artist_names(meta: &zvuk::TrackMeta) -> Option<String> {
+    (!meta.artists.is_empty()).then(|| {
+        meta.artists
+            .iter()
+            .map(|a| a.title.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    })
+}
+
+/// `cover_<size>.jpg`, for sizes from `[art] sizes`.
+pub fn cover_file(size: &str) -> String {
+    format!("cover_{}.jpg", size)
+}
+
+async fn fetch_cover(meta: &zvuk::TrackMeta, size: &str) -> Option<Vec<u8>> {
+    let src = meta.release.as_ref()?.image.as_ref()?.src.as_ref()?;
+    let url = if size == config::ORIGINAL_ART {
+        zvuk::original_cover_url(src)
+    } else {
+        zvuk::cover_url(src, &format!("{0}x{0}", size))
+    };
+    let res = zvuk::http().get(url).send().await.ok()?;
+    if !res.status().is_success() {
+        return None;
+    }
+    res.bytes().await.ok().map(|b| b.to_vec())
+}
+
+pub fn is_mp3(path: &Path) -> bool {
+    use std::io::Read;
+    let mut head = [0u8; 3];
+    std::fs::File::open(path)
+        .and_then(|mut f| f.read_exact(&mut head))
+        .is_ok()
+        && (&head == b"ID3" || (head[0] == 0xFF && head[1] & 0xE0 == 0xE0))
+}
+
+fn tag_mp3(path: &Path, meta: &zvuk::TrackMeta, cover: Option<&[u8]>) -> Result<(), id3::Error> {
+    let mut tag = Tag::read_from_path(path).unwrap_or_else(|_| Tag::new());
+    tag.set_title(&meta.title);
+    if let Some(artist) = artist_names(meta) {
+        tag.set_artist(artist);
+    }
+    if let Some(release) = &meta.release {
+        tag.set_album(&release.title);
+        if let Some(year) = release.date.as_deref().and_then(|d| d.get(..4)?.parse().ok()) {
+            tag.set_year(year);
+        }
+    }
+    if let Some(cover) = cover {
+        tag.remove_picture_by_type(PictureType::CoverFront);
+        tag.add_frame(Picture {
+            mime_type: "image/jpeg".to_string(),
+            picture_type: PictureType::CoverFront,
+            description: String::new(),
+            data: cover.to_vec(),
+        });
+    }
+    tag.write_to_path(path, Version::Id3v24)
+}
+
+/// Writes `meta.json`, `cover.jpg` and the configured `cover_<size>.jpg` into
+/// the entry and tags every MP3 among `audio`. Other formats, FLAC included,
+/// are left untagged; players can read `meta.json` and the covers instead.
+#[tracing::instrument(name = "write_metadata", skip_all)]
+pub async fn write(entry: &Path, meta: &zvuk::TrackMeta, audio: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+    tokio::fs::write(entry.join(META_FILE), serde_json::to_vec_pretty(meta)?).await?;
+    let mut cover = None;
+    for size in &config::get().art.sizes {
+        let Some(art) = fetch_cover(meta, size).await else {
+            continue;
+        };
+        tokio::fs::write(entry.join(cover_file(size)), &art).await?;
+        if size == COVER_SIZE {
+            cover = Some(art);
+        }
+    }
+    if cover.is_none() {
+        cover = fetch_cover(meta, COVER_SIZE).await;
+    }
+    if let Some(cover) = &cover {
+        tokio::fs::write(entry.join(COVER_FILE), cover).await?;
+    }
+
+    let audio: Vec<PathBuf> = audio.iter().filter(|p| is_mp3(p)).cloned().collect();
+    for path in &audio {
+        dedupe::unshare(path).await?;
+    }
+    let meta = meta.clone();
+    tokio::task::spawn_blocking(move || {
+        for path in &audio {
+            tag_mp3(path, &meta, cover.as_deref())
+                .map_err(|e| format!("couldn't tag {}: {}", path.display(), e))?;
+        }
+        Ok::<(), String>(())
+    })
+    .await??;
+    Ok(())
+}
+
+/// Writes `lyrics.lrc` for synced lyrics, `lyrics.txt` for plain ones.
+pub async fn write_lyrics(entry: &Path, lyrics: &zvuk::Lyrics) -> std::io::Result<()> {
+    let name = if lyrics.synced { LYRICS_SYNCED_FILE } else { LYRICS_PLAIN_FILE };
+    tokio::fs::write(entry.join(name), &lyrics.text).await
+}
+
+/// Audio files in an entry: downloaded qualities and transcodes.
+pub async fn audio_files(entry: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    let mut dir = tokio::fs::read_dir(entry).await?;
+    while let Some(file) = dir.next_entry().await? {
+        let path = file.path();
+        let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+        let is_part = path.extension().is_some_and(|ext| ext == PART_EXT);
+        if !is_part && matches!(stem, "best" | "mid" | "transcoded") {
+            found.push(path);
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+#[derive(Serialize, Clone)]
+pub struct Skipped {
+    pub hash: String,
+    pub reason: String,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct HydrationReport {
+    pub running: bool,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub hydrated: Vec<String>,
+    pub skipped: Vec<Skipped>,
+    pub failed: Vec<Skipped>,
+}
+
+pub static HYDRATION: Mutex<Option<HydrationReport>> = Mutex::new(None);
+
+/// The track an entry holds: as given by the caller, else from its manifest
+/// or the alias table.
+async fn track_id_for(hash: &str, entry: &Path, given: &HashMap<String, String>) -> Option<String> {
+    if let Some(id) = given.get(hash) {
+        return Some(id.clone());
+    }
+    if let Ok(raw) = tokio::fs::read(entry.join(manifest::MANIFEST_FILE)).await
+        && let Ok(m) = serde_json::from_slice::<manifest::Manifest>(&raw)
+    {
+        return Some(m.id);
+    }
+    aliases::store()
+        .by_hash(hash)
+        .ok()?
+        .into_iter()
+        .next()
+        .map(|a| a.track_id)
+}
+
+enum Unhydrated {
+    /// Nothing can be done for the entry (no track ID, no audio).
+    Skipped(String),
+    Failed(String),
+}
+
+impl<E: ToString> From<E> for Unhydrated {
+    fn from(e: E) -> Self {
+        Unhydrated::Failed(e.to_string())
+    }
+}
+
+const BUSY: &str = "a job is downloading into this entry; run hydration again once it's done";
+
+async fn hydrate_entry(
+    hash: &str,
+    entry: &Path,
+    auth_cookie: Option<&str>,
+    track_ids: &HashMap<String, String>,
+) -> Result<(), Unhydrated> {
+    if jobs::running_hashes().contains(hash) {
+        return Err(Unhydrated::Skipped(BUSY.to_string()));
+    }
+    let Some(id) = track_id_for(hash, entry, track_ids).await else {
+        let reason = "no known track ID for this entry; pass it in track_ids";
+        return Err(Unhydrated::Skipped(reason.to_string()));
+    };
+    let audio = audio_files(entry).await?;
+    if audio.is_empty() {
+        return Err(Unhydrated::Skipped("entry has no audio files".to_string()));
+    }
+    let meta = zvuk::track_meta(&id, auth_cookie).await?;
+
+    // Tagging rewrites the audio in place, so a job must not be writing it.
+    let _entries = manifest::lock_entries().await;
+    if jobs::running_hashes().contains(hash) {
+        return Err(Unhydrated::Skipped(BUSY.to_string()));
+    }
+    write(entry, &meta, &audio).await?;
+
+    let mut files = Vec::new();
+    for path in &audio {
+        let record = manifest::file_record(path).await?;
+        dedupe::share(path, &record).await?;
+        files.push(record);
+    }
+    // Keep what an existing manifest recorded beyond the metadata, e.g. the
+    // explicit variant or a streamed copy's files.
+    let manifest = match manifest::read(entry).await {
+        Ok(mut manifest) => {
+            let described = manifest::Manifest::new(&manifest.id, hash, Vec::new(), Some(&meta));
+            manifest.title = described.title;
+            manifest.artist = described.artist;
+            manifest.duration = described.duration;
+            manifest.explicit = described.explicit;
+            manifest.files.retain(|f| !files.iter().any(|new| new.name == f.name));
+            manifest.files.extend(files);
+            manifest.files.sort_by(|a, b| a.name.cmp(&b.name));
+            manifest
+        }
+        Err(_) => manifest::Manifest::new(&id, hash, files, Some(&meta)),
+    };
+    manifest::export(entry, &manifest).await?;
+    aliases::store().record(hash, &id, meta.isrc.as_deref())?;
+    Ok(())
+}
+
+/// Walks the cache and backfills metadata, tags, covers and manifests for
+/// entries that have no `meta.json` yet (downloaded before metadata existed).
+/// `track_ids` maps hashes to tracks for entries nothing else identifies.
+pub async fn hydrate(auth_cookie: Option<String>, track_ids: HashMap<String, String>) {
+    let update = |f: &dyn Fn(&mut HydrationReport)| {
+        if let Some(report) = HYDRATION.lock().unwrap().as_mut() {
+            f(report);
+        }
+    };
+
+    let mut hashes = Vec::new();
+    if let Ok(mut dir) = tokio::fs::read_dir(&*CACHEDIR).await {
+        while let Ok(Some(file)) = dir.next_entry().await {
+            if let Some(name) = file.file_name().to_str()
+                && file.path().join(source()).is_dir()
+            {
+                hashes.push(name.to_string());
+            }
+        }
+    }
+
+    for hash in track_ids.keys().filter(|hash| !hashes.contains(hash)) {
+        let reason = "no such entry in the cache".to_string();
+        update(&|r| r.skipped.push(Skipped { hash: hash.clone(), reason: reason.clone() }));
+    }
+    for hash in hashes {
+        let entry = entry_dir(&hash);
+        if entry.join(META_FILE).exists() {
+            if track_ids.contains_key(&hash) {
+                let reason = "entry already has metadata".to_string();
+                update(&|r| r.skipped.push(Skipped { hash: hash.clone(), reason: reason.clone() }));
+            }
+            continue;
+        }
+        match hydrate_entry(&hash, &entry, auth_cookie.as_deref(), &track_ids).await {
+            Ok(()) => update(&|r| r.hydrated.push(hash.clone())),
+            Err(Unhydrated::Skipped(reason)) => {
+                update(&|r| r.skipped.push(Skipped { hash: hash.clone(), reason: reason.clone() }))
+            }
+            Err(Unhydrated::Failed(reason)) => {
+                update(&|r| r.failed.push(Skipped { hash: hash.clone(), reason: reason.clone() }))
+            }
+        }
+    }
+    update(&|r| {
+        r.running = false;
+        r.finished_at = Some(crate::unix_now());
+    });
+}
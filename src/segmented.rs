@@ -0,0 +1,178 @@
+use std::{error::Error, io::SeekFrom, path::Path};
+
+use reqwest::{
+    header::{HeaderValue, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE},
+    Client, Response, StatusCode,
+};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncSeekExt, AsyncWriteExt},
+    task::JoinSet,
+};
+
+use crate::{config, throttle};
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// How a download is split into byte ranges.
+pub struct Plan {
+    pub total: u64,
+    /// `[start, end)` of each segment, in order.
+    ranges: Vec<(u64, u64)>,
+    /// Sent as `If-Range` so every segment comes from the same version.
+    validator: Option<HeaderValue>,
+}
+
+/// Splits a `206` answer to `Range: bytes=0-` into segments, or `None` if the
+/// CDN ignored the range or the file is too small to be worth splitting.
+pub fn plan(first: &Response, segments: usize) -> Option<Plan> {
+    if segments < 2 || first.status() != StatusCode::PARTIAL_CONTENT {
+        return None;
+    }
+    let headers = first.headers();
+    let total: u64 = headers.get(CONTENT_RANGE)?.to_str().ok()?.rsplit_once('/')?.1.parse().ok()?;
+    let ranges = split(total, segments, config::get().download.min_segment_bytes)?;
+    // Weak ETags aren't allowed in If-Range.
+    let validator = headers
+        .get(ETAG)
+        .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+        .or_else(|| headers.get(LAST_MODIFIED))
+        .cloned();
+    Some(Plan { total, ranges, validator })
+}
+
+/// Up to `segments` contiguous, non-empty `[start, end)` ranges covering
+/// `total` bytes, none shorter than `min_segment` (but the last), or `None`
+/// if that leaves fewer than two.
+fn split(total: u64, segments: usize, min_segment: u64) -> Option<Vec<(u64, u64)>> {
+    let count = (total / min_segment.max(1)).min(segments as u64);
+    if count < 2 {
+        return None;
+    }
+    let len = total.div_ceil(count);
+    // Rounding `len` up can leave nothing for the last few segments.
+    let count = total.div_ceil(len);
+    Some((0..count).map(|i| (i * len, ((i + 1) * len).min(total))).collect())
+}
+
+/// `Range` header value for `[start, end)`; HTTP ranges are inclusive.
+fn range(start: u64, end: u64) -> String {
+    format!("bytes={}-{}", start, end - 1)
+}
+
+/// Downloads every segment of `plan` at once into `part`, which is created at
+/// full size; `first` supplies the first segment. Returns the bytes received.
+pub async fn fetch(http: &Client, url: &str, first: Response, part: &Path, plan: &Plan) -> Result<u64, BoxError> {
+    tokio::fs::File::create(part).await?.set_len(plan.total).await?;
+
+    // Dropped on error, which aborts the segments still running.
+    let mut rest = JoinSet::new();
+    for &(start, end) in &plan.ranges[1..] {
+        let mut req = http.get(url).header(RANGE, range(start, end));
+        if let Some(validator) = &plan.validator {
+            req = req.header(IF_RANGE, validator.clone());
+        }
+        let part = part.to_path_buf();
+        rest.spawn(throttle::scoped(throttle::current(), async move {
+            let resp = req.send().await?.error_for_status()?;
+            if resp.status() != StatusCode::PARTIAL_CONTENT {
+                return Err("CDN answered a segment with the whole file; it changed mid-download".into());
+            }
+            write(resp, &part, start, end - start).await
+        }));
+    }
+
+    let (start, end) = plan.ranges[0];
+    let mut received = write(first, part, start, end - start).await?;
+    while let Some(segment) = rest.join_next().await {
+        received += segment??;
+    }
+    tracing::debug!(segments = plan.ranges.len(), "segmented download finished");
+    Ok(received)
+}
+
+/// Writes the first `len` bytes of `resp` at `offset`; anything after them
+/// is left unread.
+async fn write(mut resp: Response, part: &Path, offset: u64, len: u64) -> Result<u64, BoxError> {
+    let mut file = OpenOptions::new().write(true).open(part).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut written = 0u64;
+    while written < len
+        && let Some(chunk) = resp.chunk().await?
+    {
+        let take = chunk.len().min((len - written) as usize);
+        file.write_all(&chunk[..take]).await?;
+        written += take as u64;
+        throttle::consume(take).await;
+    }
+    file.flush().await?;
+    if written < len {
+        return Err(format!("truncated download (segment at {}: {} of {} bytes)", offset, written, len).into());
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    /// Checks that `ranges` cover `[0, total)` in order with no gaps or
+    /// empty segments.
+    fn assert_covers(ranges: &[(u64, u64)], total: u64) {
+        assert_eq!(ranges.first().unwrap().0, 0);
+        assert_eq!(ranges.last().unwrap().1, total);
+        for &(start, end) in ranges {
+            assert!(start < end, "empty segment in {:?}", ranges);
+        }
+        for pair in ranges.windows(2) {
+            assert_eq!(pair[0].1, pair[1].0);
+        }
+    }
+
+    #[test]
+    fn files_under_two_segments_arent_split() {
+        assert_eq!(split(MIB, 4, 4 * MIB), None);
+        assert_eq!(split(8 * MIB - 1, 4, 4 * MIB), None);
+        assert_eq!(split(100 * MIB, 1, 4 * MIB), None);
+        assert_eq!(split(0, 4, 0), None);
+    }
+
+    #[test]
+    fn exact_multiples_split_evenly() {
+        let ranges = split(16 * MIB, 4, 4 * MIB).unwrap();
+        assert_eq!(ranges, [(0, 4 * MIB), (4 * MIB, 8 * MIB), (8 * MIB, 12 * MIB), (12 * MIB, 16 * MIB)]);
+    }
+
+    #[test]
+    fn remainders_go_to_the_last_segment() {
+        let total = 10 * MIB + 3;
+        let ranges = split(total, 4, MIB).unwrap();
+        assert_eq!(ranges.len(), 4);
+        assert_covers(&ranges, total);
+        let (start, end) = *ranges.last().unwrap();
+        assert!(end - start <= ranges[0].1);
+    }
+
+    #[test]
+    fn small_totals_have_no_empty_segments() {
+        for total in 2..64 {
+            for segments in 2..10 {
+                if let Some(ranges) = split(total, segments, 1) {
+                    assert!(ranges.len() >= 2 && ranges.len() <= segments);
+                    assert_covers(&ranges, total);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ranges_end_on_the_last_byte() {
+        let total = 10 * MIB + 3;
+        let ranges = split(total, 4, MIB).unwrap();
+        let (start, end) = *ranges.last().unwrap();
+        assert_eq!(range(start, end), format!("bytes={}-{}", start, total - 1));
+        assert_eq!(range(0, 1), "bytes=0-0");
+    }
+}
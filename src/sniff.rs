@@ -0,0 +1,90 @@
+use std::{io::SeekFrom, path::Path};
+
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// Audio container recognized from a file's leading bytes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    Flac,
+    Mp3,
+    /// Raw AAC in ADTS frames.
+    Aac,
+    /// MP4/M4A container (usually AAC or ALAC).
+    Mp4,
+    Opus,
+    Ogg,
+    Wav,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Flac => "flac",
+            Format::Mp3 => "mp3",
+            Format::Aac => "aac",
+            Format::Mp4 => "m4a",
+            Format::Opus => "opus",
+            Format::Ogg => "ogg",
+            Format::Wav => "wav",
+        }
+    }
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            Format::Flac => "audio/flac",
+            Format::Mp3 => "audio/mpeg",
+            Format::Aac => "audio/aac",
+            Format::Mp4 => "audio/mp4",
+            Format::Opus | Format::Ogg => "audio/ogg",
+            Format::Wav => "audio/wav",
+        }
+    }
+}
+
+/// Enough for every signature below, including the Opus header inside the
+/// first Ogg page.
+const HEAD_LEN: usize = 64;
+
+/// Recognizes the format from the first bytes of a file (after any ID3v2 tag).
+pub fn detect(head: &[u8]) -> Option<Format> {
+    match head {
+        [b'f', b'L', b'a', b'C', ..] => Some(Format::Flac),
+        [b'O', b'g', b'g', b'S', ..] if head.get(28..36) == Some(b"OpusHead") => Some(Format::Opus),
+        [b'O', b'g', b'g', b'S', ..] => Some(Format::Ogg),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => Some(Format::Wav),
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => Some(Format::Mp4),
+        // Frame sync; layer bits 00 mean ADTS, anything else MPEG audio.
+        [0xFF, b, ..] if b & 0xF6 == 0xF0 => Some(Format::Aac),
+        [0xFF, b, ..] if b & 0xE0 == 0xE0 && b & 0x06 != 0 => Some(Format::Mp3),
+        _ => None,
+    }
+}
+
+/// Length of the ID3v2 tag at the start of `head`, if there is one.
+fn id3_len(head: &[u8]) -> Option<u64> {
+    let [b'I', b'D', b'3', _, _, flags, size @ ..] = head else {
+        return None;
+    };
+    let size = size.get(..4)?.iter().fold(0u64, |acc, b| (acc << 7) | u64::from(b & 0x7F));
+    let footer = if flags & 0x10 != 0 { 10 } else { 0 };
+    Some(10 + size + footer)
+}
+
+async fn read_head(file: &mut tokio::fs::File) -> std::io::Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(HEAD_LEN);
+    (&mut *file).take(HEAD_LEN as u64).read_to_end(&mut head).await?;
+    Ok(head)
+}
+
+/// Sniffs the format of a file on disk; `None` if it isn't recognized.
+pub async fn file(path: &Path) -> std::io::Result<Option<Format>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut head = read_head(&mut file).await?;
+    if let Some(offset) = id3_len(&head) {
+        file.seek(SeekFrom::Start(offset)).await?;
+        head = read_head(&mut file).await?;
+        // An ID3 tag with nothing recognizable after it is almost always MP3.
+        return Ok(Some(detect(&head).unwrap_or(Format::Mp3)));
+    }
+    Ok(detect(&head))
+}
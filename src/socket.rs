@@ -0,0 +1,48 @@
+use std::{
+    io,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use tokio::net::{UnixListener, UnixStream};
+
+/// Binds a Unix domain socket at `path` with permissions `mode`. A socket
+/// left behind by a previous run is replaced; one that still accepts
+/// connections, or a path that isn't a socket, is an error.
+pub async fn bind(path: &Path, mode: u32) -> io::Result<(UnixListener, Cleanup)> {
+    match tokio::fs::symlink_metadata(path).await {
+        Ok(meta) if meta.file_type().is_socket() => {
+            if UnixStream::connect(path).await.is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is in use by another process", path.display()),
+                ));
+            }
+            tracing::info!("removing stale socket {}", path.display());
+            tokio::fs::remove_file(path).await?;
+        }
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and isn't a socket", path.display()),
+            ));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = UnixListener::bind(path)?;
+    let cleanup = Cleanup(path.to_path_buf());
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).await?;
+    Ok((listener, cleanup))
+}
+
+/// Removes the socket file when dropped, i.e. once the server stops.
+pub struct Cleanup(PathBuf);
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            tracing::warn!("couldn't remove socket {}: {}", self.0.display(), e);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    http::{
+        header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE},
+        HeaderMap, HeaderName,
+    },
+    response::Response,
+};
+use tokio::{fs::File, io::AsyncWriteExt, sync::mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::Instrument;
+
+use crate::{aliases, dedupe, extension_for, jobs, manifest, sniff, zvuk, PART_EXT};
+
+/// Request headers passed on to the CDN.
+const FORWARDED: [HeaderName; 2] = [RANGE, IF_RANGE];
+/// CDN response headers passed back to the client.
+const PASSED_BACK: [HeaderName; 6] = [CONTENT_TYPE, CONTENT_LENGTH, CONTENT_RANGE, ACCEPT_RANGES, ETAG, LAST_MODIFIED];
+
+/// Where a full (non-range) stream is copied as it's proxied.
+pub struct Tee {
+    pub track_id: String,
+    pub hash: String,
+    pub entry: PathBuf,
+    /// File name without extension, `best` or `mid`.
+    pub name: &'static str,
+}
+
+struct Sink {
+    tee: Tee,
+    file: File,
+    part: PathBuf,
+    /// Extension from the CDN's content type, if the bytes don't tell.
+    fallback_ext: String,
+    received: u64,
+    expected: Option<u64>,
+}
+
+impl Sink {
+    async fn open(tee: Tee, fallback_ext: String, expected: Option<u64>) -> io::Result<Sink> {
+        tokio::fs::create_dir_all(&tee.entry).await?;
+        // Named apart from a job's `{name}.part` in case one starts meanwhile.
+        let part = tee.entry.join(format!("{}.stream.{}", tee.name, PART_EXT));
+        let file = File::create(&part).await?;
+        Ok(Sink { tee, file, part, fallback_ext, received: 0, expected })
+    }
+
+    async fn abandon(self) {
+        drop(self.file);
+        let _ = tokio::fs::remove_file(&self.part).await;
+    }
+
+    /// Moves the copy into place under the extension of its sniffed format,
+    /// like downloads, replacing any copy of the same quality stored under
+    /// another extension, and adds it to the entry's manifest.
+    async fn finish(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.file.flush().await?;
+        drop(self.file);
+        if let Some(expected) = self.expected
+            && expected != self.received
+        {
+            let _ = tokio::fs::remove_file(&self.part).await;
+            return Err(format!("truncated stream ({} of {} bytes)", self.received, expected).into());
+        }
+        let ext = match sniff::file(&self.part).await? {
+            Some(format) => format.extension().to_string(),
+            None => self.fallback_ext,
+        };
+        let Tee { track_id, hash, entry, name } = self.tee;
+        let file_name = if ext.is_empty() { name.to_string() } else { format!("{}.{}", name, ext) };
+        let path = entry.join(&file_name);
+        let mut record = manifest::file_record(&self.part).await?;
+        record.name = file_name;
+
+        {
+            let _entries = manifest::lock_entries().await;
+            // A job that claimed the entry meanwhile owns it; its cleanup
+            // would take this file for one of its own.
+            if jobs::running_hashes().contains(&hash) {
+                let _ = tokio::fs::remove_file(&self.part).await;
+                tracing::info!("not caching stream: a job is downloading into {}", hash);
+                return Ok(());
+            }
+            tokio::fs::rename(&self.part, &path).await?;
+            let stale = other_copies(&entry, name, &record.name).await?;
+            for stale in &stale {
+                tokio::fs::remove_file(entry.join(stale)).await?;
+            }
+            let mut m = manifest::read(&entry)
+                .await
+                .unwrap_or_else(|_| manifest::Manifest::new(&track_id, &hash, Vec::new(), None));
+            m.files.retain(|f| f.name != record.name && !stale.contains(&f.name));
+            m.files.push(record.clone());
+            m.files.sort_by(|a, b| a.name.cmp(&b.name));
+            manifest::export(&entry, &m).await?;
+        }
+        dedupe::share(&path, &record).await?;
+        aliases::store().record(&hash, &track_id, None)?;
+        tracing::info!(bytes = self.received, "cached stream as {}", path.display());
+        Ok(())
+    }
+}
+
+/// Files in `entry` holding quality `name` under a name other than `keep`,
+/// e.g. `best.m2a` once `best.flac` is stored.
+async fn other_copies(entry: &Path, name: &str, keep: &str) -> io::Result<Vec<String>> {
+    let mut found = Vec::new();
+    let mut dir = tokio::fs::read_dir(entry).await?;
+    while let Some(item) = dir.next_entry().await? {
+        let path = item.path();
+        let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if file_name != keep
+            && path.file_stem().and_then(|s| s.to_str()) == Some(name)
+            && path.extension().and_then(|e| e.to_str()) != Some(PART_EXT)
+        {
+            found.push(file_name.to_string());
+        }
+    }
+    Ok(found)
+}
+
+/// Fetches `url` from the CDN with the client's range headers and streams the
+/// response back unchanged. With `tee`, a complete (`200`) response is also
+/// written into the cache; range responses never are.
+pub async fn proxy(url: &str, headers: &HeaderMap, tee: Option<Tee>) -> reqwest::Result<Response> {
+    let mut upstream = zvuk::http().get(url);
+    for name in FORWARDED {
+        if let Some(value) = headers.get(&name) {
+            upstream = upstream.header(name, value);
+        }
+    }
+    let res = upstream.send().await?;
+
+    let mut response = Response::builder().status(res.status());
+    for name in PASSED_BACK {
+        if let Some(value) = res.headers().get(&name) {
+            response = response.header(name, value);
+        }
+    }
+    let tee = tee.filter(|_| res.status() == reqwest::StatusCode::OK);
+    let (tx, rx) = mpsc::channel(8);
+    tokio::spawn(pump(res, tx, tee).in_current_span());
+    Ok(response
+        .body(Body::from_stream(ReceiverStream::new(rx)))
+        .expect("CDN headers are valid"))
+}
+
+async fn pump(mut res: reqwest::Response, tx: mpsc::Sender<io::Result<Bytes>>, tee: Option<Tee>) {
+    let ext = extension_for(res.headers().get(CONTENT_TYPE).and_then(|h| h.to_str().ok()));
+    let mut sink = match tee {
+        Some(tee) => Sink::open(tee, ext, res.content_length())
+            .await
+            .inspect_err(|e| tracing::warn!("couldn't cache stream: {}", e))
+            .ok(),
+        None => None,
+    };
+    loop {
+        let chunk = match res.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(e) => {
+                let _ = tx.send(Err(io::Error::other(e))).await;
+                if let Some(sink) = sink {
+                    sink.abandon().await;
+                }
+                return;
+            }
+        };
+        if let Some(s) = &mut sink {
+            s.received += chunk.len() as u64;
+            if let Err(e) = s.file.write_all(&chunk).await {
+                tracing::warn!("couldn't cache stream: {}", e);
+                sink.take().unwrap().abandon().await;
+            }
+        }
+        if tx.send(Ok(chunk)).await.is_err() {
+            // The client went away; a partial copy is useless.
+            if let Some(sink) = sink {
+                sink.abandon().await;
+            }
+            return;
+        }
+    }
+    if let Some(sink) = sink
+        && let Err(e) = sink.finish().await
+    {
+        tracing::warn!("couldn't cache stream: {}", e);
+    }
+}
@@ -0,0 +1,144 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{auth::Principal, config, dedupe};
+
+/// How a tenant's TRILIB consumer encodes track hashes.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum HashScheme {
+    /// Used verbatim; must be a single safe path segment.
+    #[default]
+    Raw,
+    Sha1Hex,
+    Base64url,
+    Uuid,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Tenant {
+    pub hash_scheme: HashScheme,
+    /// API keys (from `TRI_ZVUK_API_KEYS`) that belong to this tenant.
+    pub api_keys: Vec<String>,
+}
+
+pub fn valid_hash(hash: &str) -> bool {
+    !hash.is_empty() && hash != "." && hash != ".." && hash != dedupe::BLOB_DIR && !hash.contains(['/', '\\'])
+}
+
+fn hex_bytes(s: &str, len: usize) -> Option<String> {
+    (s.len() == len * 2 && s.bytes().all(|b| b.is_ascii_hexdigit())).then(|| s.to_ascii_lowercase())
+}
+
+impl HashScheme {
+    /// Maps a client hash onto the canonical cache key: lowercase hex of the
+    /// underlying bytes for the binary schemes, the hash itself for `raw`.
+    pub fn canonicalize(self, hash: &str) -> Result<String, String> {
+        let hash = hash.trim();
+        let canonical = match self {
+            HashScheme::Raw => valid_hash(hash).then(|| hash.to_string()),
+            HashScheme::Sha1Hex => hex_bytes(hash, 20),
+            HashScheme::Base64url => URL_SAFE_NO_PAD
+                .decode(hash.trim_end_matches('='))
+                .ok()
+                .filter(|bytes| !bytes.is_empty())
+                .map(hex::encode),
+            HashScheme::Uuid => hex_bytes(
+                &hash.trim_start_matches('{').trim_end_matches('}').replace('-', ""),
+                16,
+            ),
+        };
+        canonical.ok_or_else(|| format!("hash {:?} is not valid for the {:?} scheme", hash, self))
+    }
+}
+
+/// Cache key for tracks enqueued by the service itself (e.g. discographies)
+/// rather than with a client hash: 40 hex chars, valid under every scheme's
+/// canonical form.
+pub fn track_hash(track_id: &str) -> String {
+    hex::encode(&Sha256::digest(format!("zvuk:track:{}", track_id))[..20])
+}
+
+pub fn for_key(key: &str) -> Option<String> {
+    config::get()
+        .tenants
+        .iter()
+        .find(|(_, tenant)| tenant.api_keys.iter().any(|k| k == key))
+        .map(|(name, _)| name.clone())
+}
+
+pub fn scheme(principal: Option<&Principal>) -> HashScheme {
+    let config = config::get();
+    principal
+        .and_then(Principal::tenant)
+        .and_then(|name| config.tenants.get(name))
+        .map(|tenant| tenant.hash_scheme)
+        .unwrap_or(config.default_hash_scheme)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA1: &str = "0123456789abcdef0123456789abcdef01234567";
+    const UUID: &str = "123e4567e89b12d3a456426614174000";
+
+    fn rejects(scheme: HashScheme, hashes: &[&str]) {
+        for hash in hashes {
+            assert!(scheme.canonicalize(hash).is_err(), "{:?} accepted {:?}", scheme, hash);
+        }
+    }
+
+    #[test]
+    fn raw_hashes_are_kept_verbatim() {
+        assert_eq!(HashScheme::Raw.canonicalize("AbC-123").unwrap(), "AbC-123");
+        assert_eq!(HashScheme::Raw.canonicalize(" abc\n").unwrap(), "abc");
+        rejects(HashScheme::Raw, &["", " ", ".", "..", "a/b", "../etc", "a\\b", "/abc", dedupe::BLOB_DIR]);
+    }
+
+    #[test]
+    fn sha1_hex_is_lowercased() {
+        assert_eq!(HashScheme::Sha1Hex.canonicalize(SHA1).unwrap(), SHA1);
+        assert_eq!(HashScheme::Sha1Hex.canonicalize(&SHA1.to_uppercase()).unwrap(), SHA1);
+        rejects(
+            HashScheme::Sha1Hex,
+            &[&SHA1[1..], &format!("{}0", SHA1), &format!("0x{}", &SHA1[2..]), &SHA1.replace('a', "g"), "..", "a/b"],
+        );
+    }
+
+    #[test]
+    fn base64url_is_decoded_to_hex() {
+        assert_eq!(HashScheme::Base64url.canonicalize("AQID").unwrap(), "010203");
+        assert_eq!(HashScheme::Base64url.canonicalize("AQI").unwrap(), "0102");
+        assert_eq!(HashScheme::Base64url.canonicalize("AQI=").unwrap(), "0102");
+        assert_eq!(HashScheme::Base64url.canonicalize("-_8").unwrap(), "fbff");
+        // Standard base64's `+` and `/` aren't part of the URL-safe alphabet.
+        rejects(HashScheme::Base64url, &["", "=", "+/8", "..", "a/b", "A"]);
+    }
+
+    #[test]
+    fn uuids_lose_braces_dashes_and_case() {
+        for hash in [
+            "123e4567-e89b-12d3-a456-426614174000",
+            "123E4567-E89B-12D3-A456-426614174000",
+            "{123e4567-e89b-12d3-a456-426614174000}",
+            UUID,
+        ] {
+            assert_eq!(HashScheme::Uuid.canonicalize(hash).unwrap(), UUID);
+        }
+        rejects(HashScheme::Uuid, &["", &UUID[1..], &UUID.replace('e', "z"), "urn:uuid:x", "..", "a/b"]);
+    }
+
+    #[test]
+    fn canonical_forms_are_safe_cache_keys() {
+        for (scheme, hash) in [
+            (HashScheme::Sha1Hex, SHA1),
+            (HashScheme::Base64url, "Li4vLi4"),
+            (HashScheme::Uuid, UUID),
+        ] {
+            assert!(valid_hash(&scheme.canonicalize(hash).unwrap()));
+        }
+    }
+}
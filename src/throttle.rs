@@ -0,0 +1,152 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use tokio::time::Instant;
+
+use crate::config;
+
+/// Token bucket counting bytes. Takers may overdraw it and wait off the
+/// debt, so concurrent downloads end up sharing the rate.
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new() -> Self {
+        Bucket { tokens: 0.0, last: Instant::now() }
+    }
+
+    /// Takes `bytes` at `rate` bytes per second and returns how long to wait
+    /// before using them. At most one second's worth builds up while idle.
+    fn take(&mut self, bytes: usize, rate: f64) -> Duration {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * rate;
+        self.tokens = (self.tokens + refill).min(rate) - bytes as f64;
+        self.last = now;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+/// Bytes per second for a limit in kbps; 0 means unlimited.
+fn bytes_per_sec(kbps: u32) -> Option<f64> {
+    (kbps > 0).then(|| f64::from(kbps) * 1000.0 / 8.0)
+}
+
+/// Shared by every download; the rate is read on each take so it follows
+/// config reloads.
+static GLOBAL: Lazy<Mutex<Bucket>> = Lazy::new(|| Mutex::new(Bucket::new()));
+
+/// One job's limit, shared by its qualities and segments.
+#[derive(Clone)]
+pub struct Limit {
+    rate: f64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+tokio::task_local! {
+    static JOB: Option<Limit>;
+}
+
+/// Runs `f` with its downloads held to `max_kbps`, on top of
+/// `[download] max_kbps`.
+pub async fn with_limit<F: Future>(max_kbps: Option<u32>, f: F) -> F::Output {
+    let limit = max_kbps
+        .and_then(bytes_per_sec)
+        .map(|rate| Limit { rate, bucket: Arc::new(Mutex::new(Bucket::new())) });
+    JOB.scope(limit, f).await
+}
+
+/// The limit [`with_limit`] set for the current task, to carry into tasks
+/// it spawns with [`scoped`].
+pub fn current() -> Option<Limit> {
+    JOB.try_with(Clone::clone).ok().flatten()
+}
+
+pub async fn scoped<F: Future>(limit: Option<Limit>, f: F) -> F::Output {
+    JOB.scope(limit, f).await
+}
+
+/// Waits until `bytes` just received may be passed on under the global and
+/// the job's limit.
+pub async fn consume(bytes: usize) {
+    let global = config::get().download.max_kbps.and_then(bytes_per_sec);
+    let wait = delay(bytes, global.map(|rate| (&*GLOBAL, rate)), current().as_ref());
+    if !wait.is_zero() {
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// How long `bytes` have to wait under `global` and `job`, whichever is
+/// further behind.
+fn delay(bytes: usize, global: Option<(&Mutex<Bucket>, f64)>, job: Option<&Limit>) -> Duration {
+    let mut wait = Duration::ZERO;
+    if let Some((bucket, rate)) = global {
+        wait = bucket.lock().unwrap().take(bytes, rate);
+    }
+    if let Some(limit) = job {
+        wait = wait.max(limit.bucket.lock().unwrap().take(bytes, limit.rate));
+    }
+    wait
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn limit(rate: f64) -> Limit {
+        Limit { rate, bucket: Arc::new(Mutex::new(Bucket::new())) }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn takers_wait_off_what_they_overdraw() {
+        let mut bucket = Bucket::new();
+        assert_eq!(bucket.take(500, 1000.0), SECOND / 2);
+        tokio::time::advance(SECOND / 2).await;
+        assert_eq!(bucket.take(500, 1000.0), SECOND / 2);
+        assert_eq!(bucket.take(1000, 1000.0), SECOND + SECOND / 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_buckets_fill_up_to_one_second() {
+        let mut bucket = Bucket::new();
+        tokio::time::advance(10 * SECOND).await;
+        assert_eq!(bucket.take(1000, 1000.0), Duration::ZERO);
+        assert_eq!(bucket.take(1000, 1000.0), SECOND);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slower_of_global_and_job_limit_wins() {
+        let global = Mutex::new(Bucket::new());
+        assert_eq!(delay(1000, Some((&global, 2000.0)), Some(&limit(1000.0))), SECOND);
+        let global = Mutex::new(Bucket::new());
+        assert_eq!(delay(1000, Some((&global, 1000.0)), Some(&limit(2000.0))), SECOND);
+        assert_eq!(delay(1000, None, Some(&limit(1000.0))), SECOND);
+        assert_eq!(delay(1000, None, None), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn jobs_share_the_global_limit() {
+        let global = Mutex::new(Bucket::new());
+        let (first, second) = (limit(10_000.0), limit(10_000.0));
+        assert_eq!(delay(1000, Some((&global, 1000.0)), Some(&first)), SECOND);
+        assert_eq!(delay(1000, Some((&global, 1000.0)), Some(&second)), 2 * SECOND);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn zero_is_unlimited() {
+        assert_eq!(bytes_per_sec(0), None);
+        assert_eq!(bytes_per_sec(8), Some(1000.0));
+        assert!(with_limit(Some(0), async { current().is_none() }).await);
+        assert!(with_limit(Some(8), async { current().is_some() }).await);
+    }
+}
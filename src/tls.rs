@@ -0,0 +1,162 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::timeout,
+};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tokio_rustls::{
+    rustls::{
+        self,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+use crate::config;
+
+/// How long a client gets to finish the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reads and checks the certificate chain and key named in `tls`.
+fn load(tls: &config::Tls) -> Result<CertifiedKey, String> {
+    let certs = CertificateDer::pem_file_iter(&tls.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("couldn't read {}: {}", tls.cert.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates in {}", tls.cert.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(&tls.key)
+        .map_err(|e| format!("couldn't read {}: {}", tls.key.display(), e))?;
+    let key = rustls::crypto::ring::sign::any_supported_type(&key)
+        .map_err(|e| format!("unusable key in {}: {}", tls.key.display(), e))?;
+    let certified = CertifiedKey::new(certs, key);
+    certified
+        .keys_match()
+        .map_err(|e| format!("{} doesn't match {}: {}", tls.key.display(), tls.cert.display(), e))?;
+    Ok(certified)
+}
+
+/// Hands out the current certificate, which [`watch`] swaps on renewal.
+#[derive(Debug)]
+struct Resolver(RwLock<Arc<CertifiedKey>>);
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, _hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.read().unwrap().clone())
+    }
+}
+
+fn modified(tls: &config::Tls) -> [Option<SystemTime>; 2] {
+    [&tls.cert, &tls.key].map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+}
+
+/// Reloads the certificate whenever its files change; a broken renewal is
+/// logged and the previous certificate kept.
+async fn watch(resolver: Arc<Resolver>, tls: config::Tls) {
+    if tls.reload_interval_secs == 0 {
+        return;
+    }
+    let mut seen = modified(&tls);
+    let mut interval = tokio::time::interval(Duration::from_secs(tls.reload_interval_secs));
+    loop {
+        interval.tick().await;
+        let now = modified(&tls);
+        if now == seen {
+            continue;
+        }
+        seen = now;
+        match load(&tls) {
+            Ok(certified) => {
+                *resolver.0.write().unwrap() = Arc::new(certified);
+                tracing::info!("reloaded TLS certificate from {}", tls.cert.display());
+            }
+            Err(e) => tracing::warn!("keeping the current TLS certificate: {}", e),
+        }
+    }
+}
+
+/// Accepts TLS connections on a TCP listener. Handshakes run in their own
+/// tasks, so a slow client can't hold up the others.
+pub struct TlsListener {
+    incoming: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+/// Wraps `tcp` in TLS with the certificate from `tls`, which is reloaded
+/// when it's renewed, offering `alpn` (e.g. `http/1.1`, or `h2` for gRPC).
+pub fn listen(tcp: TcpListener, tls: &config::Tls, alpn: &[u8]) -> Result<TlsListener, String> {
+    let resolver = Arc::new(Resolver(RwLock::new(Arc::new(load(tls)?))));
+    let mut server = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+    server.alpn_protocols = vec![alpn.to_vec()];
+    tokio::spawn(watch(resolver, tls.clone()));
+
+    let local_addr = tcp.local_addr().map_err(|e| e.to_string())?;
+    let (tx, incoming) = mpsc::channel(64);
+    tokio::spawn(accept(tcp, TlsAcceptor::from(Arc::new(server)), tx));
+    Ok(TlsListener { incoming, local_addr })
+}
+
+async fn accept(tcp: TcpListener, acceptor: TlsAcceptor, tx: mpsc::Sender<(TlsStream<TcpStream>, SocketAddr)>) {
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = tcp.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!("couldn't accept connection: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            // The server stopped.
+            _ = tx.closed() => return,
+        };
+        let acceptor = acceptor.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    let _ = tx.send((stream, addr)).await;
+                }
+                Ok(Err(e)) => tracing::debug!(%addr, "TLS handshake failed: {}", e),
+                Err(_) => tracing::debug!(%addr, "TLS handshake timed out"),
+            }
+        });
+    }
+}
+
+impl TlsListener {
+    /// The accepted connections as a stream, for servers other than axum's.
+    pub fn into_stream(self) -> impl futures_util::Stream<Item = io::Result<TlsStream<TcpStream>>> {
+        ReceiverStream::new(self.incoming).map(|(stream, _)| Ok(stream))
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.incoming.recv().await {
+            Some(accepted) => accepted,
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
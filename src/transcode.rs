@@ -0,0 +1,115 @@
+use std::{env, error::Error, path::{Path, PathBuf}};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+pub static FFMPEG: Lazy<String> =
+    Lazy::new(|| env::var("TRI_ZVUK_FFMPEG").unwrap_or_else(|_| "ffmpeg".to_string()));
+static FFPROBE: Lazy<String> =
+    Lazy::new(|| env::var("TRI_ZVUK_FFPROBE").unwrap_or_else(|_| "ffprobe".to_string()));
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Mp3,
+    Opus,
+    Aac,
+}
+
+impl Codec {
+    fn encoder(self) -> &'static str {
+        match self {
+            Codec::Mp3 => "libmp3lame",
+            Codec::Opus => "libopus",
+            Codec::Aac => "aac",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Codec::Mp3 => "mp3",
+            Codec::Opus => "opus",
+            Codec::Aac => "m4a",
+        }
+    }
+
+    fn default_bitrate(self) -> u32 {
+        match self {
+            Codec::Mp3 => 192,
+            Codec::Opus => 128,
+            Codec::Aac => 192,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Transcode {
+    pub codec: Codec,
+    /// Target bitrate in kbps.
+    pub bitrate: Option<u32>,
+}
+
+/// Re-encodes `src` with ffmpeg into `dir/transcoded.<ext>` and returns the
+/// written path.
+pub async fn run(src: &Path, dir: &Path, opts: &Transcode) -> Result<PathBuf, Box<dyn Error>> {
+    let bitrate = opts.bitrate.unwrap_or(opts.codec.default_bitrate());
+    if !(32..=512).contains(&bitrate) {
+        return Err(format!("bitrate {}k is out of range (32-512)", bitrate).into());
+    }
+    let out = dir.join(format!("transcoded.{}", opts.codec.extension()));
+
+    let output = Command::new(FFMPEG.as_str())
+        .args(["-y", "-hide_banner", "-loglevel", "error", "-i"])
+        .arg(src)
+        .args(["-vn", "-c:a", opts.codec.encoder(), "-b:a"])
+        .arg(format!("{}k", bitrate))
+        .arg(&out)
+        .output()
+        .await
+        .map_err(|e| format!("couldn't run {}: {}", FFMPEG.as_str(), e))?;
+
+    if !output.status.success() {
+        let _ = tokio::fs::remove_file(&out).await;
+        return Err(format!(
+            "ffmpeg exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(out)
+}
+
+/// What ffprobe reports about an audio file.
+pub struct Probe {
+    pub codec: Option<String>,
+    pub duration_secs: Option<f64>,
+    pub bitrate_kbps: Option<u32>,
+}
+
+pub async fn probe(path: &Path) -> Result<Probe, Box<dyn Error>> {
+    let output = Command::new(FFPROBE.as_str())
+        .args(["-v", "error", "-select_streams", "a:0"])
+        .args(["-show_entries", "format=duration,bit_rate:stream=codec_name", "-of", "json"])
+        .arg(path)
+        .output()
+        .await
+        .map_err(|e| format!("couldn't run {}: {}", FFPROBE.as_str(), e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "ffprobe exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    let number = |v: &serde_json::Value| v.as_str().and_then(|s| s.parse::<f64>().ok());
+    Ok(Probe {
+        codec: report["streams"][0]["codec_name"].as_str().map(String::from),
+        duration_secs: number(&report["format"]["duration"]),
+        bitrate_kbps: number(&report["format"]["bit_rate"]).map(|bps| (bps / 1000.0).round() as u32),
+    })
+}
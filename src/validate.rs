@@ -0,0 +1,82 @@
+use axum::{
+    extract::{rejection::JsonRejection, FromRequest, OptionalFromRequest, Request},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::de::DeserializeOwned;
+
+use crate::error::ApiError;
+
+/// Most track IDs accepted in one request body.
+pub const MAX_IDS: usize = 5000;
+
+/// Checks on a request body that deserializing it doesn't cover.
+pub trait Validate {
+    fn validate(&self) -> Result<(), String>;
+}
+
+/// A JSON body that passed [`Validate`]. Bodies that don't parse keep the
+/// status of their rejection (`400` for broken JSON, `413`, `415`, `422` for
+/// missing or mistyped fields); invalid ones get `422`. Either way the error
+/// says what's wrong.
+pub struct Valid<T>(pub T);
+
+impl<S: Send + Sync, T: DeserializeOwned + Validate> FromRequest<S> for Valid<T> {
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = <Json<T> as FromRequest<S>>::from_request(req, state).await.map_err(rejected)?;
+        value.validate().map_err(|error| error_response(StatusCode::UNPROCESSABLE_ENTITY, error))?;
+        Ok(Valid(value))
+    }
+}
+
+/// An optional body: `None` without a `Content-Type`, rejected like
+/// [`Valid`] otherwise.
+impl<S: Send + Sync, T: DeserializeOwned + Validate> OptionalFromRequest<S> for Valid<T> {
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Option<Self>, Self::Rejection> {
+        let Some(Json(value)) =
+            <Json<T> as OptionalFromRequest<S>>::from_request(req, state).await.map_err(rejected)?
+        else {
+            return Ok(None);
+        };
+        value.validate().map_err(|error| error_response(StatusCode::UNPROCESSABLE_ENTITY, error))?;
+        Ok(Some(Valid(value)))
+    }
+}
+
+fn rejected(rejection: JsonRejection) -> Response {
+    error_response(rejection.status(), rejection.body_text())
+}
+
+fn error_response(status: StatusCode, error: String) -> Response {
+    ApiError::new(status, error).into_response()
+}
+
+pub fn non_empty(field: &str, value: &str) -> Result<(), String> {
+    if value.trim().is_empty() {
+        return Err(format!("{} must not be empty", field));
+    }
+    Ok(())
+}
+
+pub fn positive(field: &str, value: Option<u32>) -> Result<(), String> {
+    if value == Some(0) {
+        return Err(format!("{} must be above 0", field));
+    }
+    Ok(())
+}
+
+/// At most [`MAX_IDS`] entries, none of them blank.
+pub fn ids(field: &str, ids: &[String]) -> Result<(), String> {
+    if ids.len() > MAX_IDS {
+        return Err(format!("{} has {} entries; at most {} are allowed", field, ids.len(), MAX_IDS));
+    }
+    if let Some(i) = ids.iter().position(|id| id.trim().is_empty()) {
+        return Err(format!("{}[{}] must not be empty", field, i));
+    }
+    Ok(())
+}
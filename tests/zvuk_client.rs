@@ -0,0 +1,247 @@
+use reqwest::{Client, StatusCode};
+use serde_json::json;
+use trilib_zvuk::zvuk::{ApiError, AuthExpired, GraphQLError, TrackNotFound, ZvukClient};
+use wiremock::{
+    matchers::{body_partial_json, header, method, path},
+    Mock, MockBuilder, MockServer, ResponseTemplate,
+};
+
+async fn stub() -> (MockServer, ZvukClient) {
+    let server = MockServer::start().await;
+    let client = ZvukClient::new(Client::new(), &format!("{}/api", server.uri()), None);
+    (server, client)
+}
+
+fn graphql(operation: &str) -> MockBuilder {
+    Mock::given(method("POST"))
+        .and(path("/api/v1/graphql"))
+        .and(body_partial_json(json!({ "operationName": operation })))
+}
+
+fn stream_response() -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(json!({
+        "data": { "mediaContents": [{ "stream": {
+            "expire": "2030-01-01T00:00:00",
+            "high": "https://cdn.zvuk.example/track/high.mp3?token=abc",
+            "mid": "https://cdn.zvuk.example/track/mid.mp3?token=abc",
+        }}]}
+    }))
+}
+
+#[tokio::test]
+async fn stream_urls_returns_high_then_mid() {
+    let (server, client) = stub().await;
+    graphql("getStream")
+        .and(header("Cookie", "session=1"))
+        .and(body_partial_json(json!({ "variables": { "ids": ["42"] } })))
+        .respond_with(stream_response())
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let urls = client.stream_urls("42", "session=1").await.unwrap();
+    assert_eq!(
+        urls,
+        [
+            "https://cdn.zvuk.example/track/high.mp3?token=abc",
+            "https://cdn.zvuk.example/track/mid.mp3?token=abc",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn stream_urls_are_moved_onto_the_cdn_url() {
+    let server = MockServer::start().await;
+    let client = ZvukClient::new(Client::new(), &format!("{}/api", server.uri()), Some("http://mirror.local/"));
+    graphql("getStream").respond_with(stream_response()).mount(&server).await;
+
+    let urls = client.stream_urls("42", "session=1").await.unwrap();
+    assert_eq!(urls[0], "http://mirror.local/track/high.mp3?token=abc");
+    assert_eq!(urls[1], "http://mirror.local/track/mid.mp3?token=abc");
+}
+
+#[tokio::test]
+async fn forbidden_is_reported_as_expired_session() {
+    let (server, client) = stub().await;
+    graphql("getStream").respond_with(ResponseTemplate::new(403)).mount(&server).await;
+
+    let err = client.stream_urls("42", "session=1").await.unwrap_err();
+    let auth = err.downcast_ref::<AuthExpired>().expect("AuthExpired");
+    assert_eq!(auth.status, Some(StatusCode::FORBIDDEN));
+}
+
+#[tokio::test]
+async fn graphql_auth_errors_are_reported_as_expired_session() {
+    let (server, client) = stub().await;
+    graphql("getStream")
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "data": { "mediaContents": [{ "stream": null }] },
+            "errors": [{ "message": "Unauthorized" }],
+        })))
+        .mount(&server)
+        .await;
+
+    let err = client.stream_urls("42", "session=1").await.unwrap_err();
+    let auth = err.downcast_ref::<AuthExpired>().expect("AuthExpired");
+    assert_eq!(auth.status, None);
+}
+
+#[tokio::test]
+async fn malformed_stream_response_is_an_error() {
+    let (server, client) = stub().await;
+    graphql("getStream")
+        .respond_with(ResponseTemplate::new(200).set_body_string("{\"data\": [not json"))
+        .mount(&server)
+        .await;
+
+    let err = client.stream_urls("42", "session=1").await.unwrap_err();
+    assert!(err.downcast_ref::<serde_json::Error>().is_some(), "{}", err);
+}
+
+#[tokio::test]
+async fn missing_stream_is_an_error() {
+    let (server, client) = stub().await;
+    graphql("getStream")
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "data": { "mediaContents": [null] } })))
+        .mount(&server)
+        .await;
+
+    let err = client.stream_urls("42", "session=1").await.unwrap_err();
+    assert!(err.to_string().contains("not found"), "{}", err);
+}
+
+#[tokio::test]
+async fn empty_media_contents_is_track_not_found() {
+    let (server, client) = stub().await;
+    graphql("getStream")
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "data": { "mediaContents": [] } })))
+        .mount(&server)
+        .await;
+
+    let err = client.stream_urls("42", "session=1").await.unwrap_err();
+    let TrackNotFound(id) = err.downcast_ref::<TrackNotFound>().expect("TrackNotFound");
+    assert_eq!(id, "42");
+}
+
+#[tokio::test]
+async fn stream_without_mid_returns_high_only() {
+    let (server, client) = stub().await;
+    graphql("getStream")
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "data": { "mediaContents": [{ "stream": { "high": "https://cdn.zvuk.example/high.flac" } }] }
+        })))
+        .mount(&server)
+        .await;
+
+    let urls = client.stream_urls("42", "session=1").await.unwrap();
+    assert_eq!(urls, ["https://cdn.zvuk.example/high.flac"]);
+}
+
+#[tokio::test]
+async fn graphql_errors_without_data_are_reported() {
+    let (server, client) = stub().await;
+    graphql("getStream")
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "data": null,
+            "errors": [{ "message": "Internal server error", "extensions": { "code": "INTERNAL" } }],
+        })))
+        .mount(&server)
+        .await;
+
+    let err = client.stream_urls("42", "session=1").await.unwrap_err();
+    let graphql = err.downcast_ref::<GraphQLError>().expect("GraphQLError");
+    assert_eq!(graphql.operation, "getStream");
+    assert_eq!(graphql.messages, ["Internal server error"]);
+}
+
+#[tokio::test]
+async fn graphql_auth_error_codes_are_reported_as_expired_session() {
+    let (server, client) = stub().await;
+    graphql("getTracks")
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "data": { "getTracks": [null] },
+            "errors": [{ "message": "denied", "extensions": { "code": "UNAUTHENTICATED" } }],
+        })))
+        .mount(&server)
+        .await;
+
+    let err = client.track_meta("42", None).await.unwrap_err();
+    assert!(err.downcast_ref::<AuthExpired>().is_some(), "{}", err);
+}
+
+#[tokio::test]
+async fn track_meta_parses_the_track() {
+    let (server, client) = stub().await;
+    graphql("getTracks")
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "data": { "getTracks": [{
+                "id": "42",
+                "title": "Song",
+                "duration": 215,
+                "explicit": false,
+                "isrc": "RUA1D1234567",
+                "artists": [{ "id": "7", "title": "Artist" }],
+                "release": { "id": "9", "title": "Album", "date": "2021-05-01", "image": null },
+            }]}
+        })))
+        .mount(&server)
+        .await;
+
+    let meta = client.track_meta("42", None).await.unwrap();
+    assert_eq!(meta.title, "Song");
+    assert_eq!(meta.duration, Some(215));
+    assert_eq!(meta.artists[0].title, "Artist");
+    assert_eq!(meta.release.unwrap().title, "Album");
+}
+
+#[tokio::test]
+async fn track_meta_reports_server_errors() {
+    let (server, client) = stub().await;
+    graphql("getTracks").respond_with(ResponseTemplate::new(502)).mount(&server).await;
+
+    let err = client.track_meta("42", None).await.unwrap_err();
+    let ApiError(status) = err.downcast_ref::<ApiError>().expect("ApiError");
+    assert_eq!(*status, StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn track_meta_rejects_malformed_json() {
+    let (server, client) = stub().await;
+    graphql("getTracks")
+        .respond_with(ResponseTemplate::new(200).set_body_string("<html>maintenance</html>"))
+        .mount(&server)
+        .await;
+
+    assert!(client.track_meta("42", None).await.is_err());
+}
+
+#[tokio::test]
+async fn validate_accepts_signed_in_profiles_only() {
+    let (server, client) = stub().await;
+    Mock::given(method("GET"))
+        .and(path("/api/tiny/profile"))
+        .and(header("Cookie", "good"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "result": { "profile": { "id": 1 }, "is_anonymous": false }
+        })))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/api/tiny/profile"))
+        .and(header("Cookie", "anonymous"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "result": { "id": 2, "is_anonymous": true }
+        })))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/api/tiny/profile"))
+        .and(header("Cookie", "expired"))
+        .respond_with(ResponseTemplate::new(403))
+        .mount(&server)
+        .await;
+
+    assert!(client.validate("good").await.unwrap());
+    assert!(!client.validate("anonymous").await.unwrap());
+    assert!(!client.validate("expired").await.unwrap());
+}